@@ -0,0 +1,497 @@
+pub mod assembler;
+pub mod bigint;
+pub mod bytecode;
+pub mod compiler;
+pub mod debugger;
+pub mod decompiler;
+pub mod fuzz;
+pub mod input;
+pub mod linter;
+pub mod opcodes;
+pub mod optimizer;
+pub mod output;
+pub mod profiler;
+pub mod runner;
+pub mod symbolic;
+pub mod threaded;
+pub mod word;
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+
+use opcodes::{Cpu, Custom, Flow, InstructionSet, Opcode};
+use optimizer::{Block, Execution};
+use word::Word;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Operand {
+    Register(usize),
+    Value(i32),
+}
+
+impl FromStr for Operand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "a" => Ok(Operand::Register(0)),
+            "b" => Ok(Operand::Register(1)),
+            "c" => Ok(Operand::Register(2)),
+            "d" => Ok(Operand::Register(3)),
+            _ => s
+                .parse()
+                .map(Operand::Value)
+                .map_err(|_| format!("Unknown value: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Register(r) => write!(f, "{}", register_name(*r)),
+            Operand::Value(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+pub fn register_name(register: usize) -> char {
+    (b'a' + register as u8) as char
+}
+
+// Every argument is an operand, even the ones that should be registers. 'tgl' can turn a valid
+// instruction into an invalid one, e.g. 'jnz 1 2' into 'cpy 1 2', and those still need to be
+// representable.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Instruction {
+    Cpy(Operand, Operand),
+    Inc(Operand),
+    Dec(Operand),
+    Jnz(Operand, Operand),
+    Tgl(Operand),
+    Out(Operand),
+    In(Operand),
+    // A registered opcode that is not built in
+    Custom(Custom),
+}
+
+impl Instruction {
+    // The opcode with its operands. Unused operands are padding.
+    pub fn decode(self) -> (&'static dyn Opcode, [Operand; 2]) {
+        let unused = Operand::Value(0);
+        match self {
+            Instruction::Cpy(x, y) => (&opcodes::Cpy, [x, y]),
+            Instruction::Inc(x) => (&opcodes::Inc, [x, unused]),
+            Instruction::Dec(x) => (&opcodes::Dec, [x, unused]),
+            Instruction::Jnz(x, y) => (&opcodes::Jnz, [x, y]),
+            Instruction::Tgl(x) => (&opcodes::Tgl, [x, unused]),
+            Instruction::Out(x) => (&opcodes::Out, [x, unused]),
+            Instruction::In(x) => (&opcodes::In, [x, unused]),
+            Instruction::Custom(custom) => (custom.opcode, custom.operands),
+        }
+    }
+
+    pub fn toggled(self) -> Self {
+        let (opcode, operands) = self.decode();
+        opcode.toggled(&operands[..opcode.arity()])
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (opcode, operands) = self.decode();
+        write!(f, "{}", opcode.name())?;
+        for operand in &operands[..opcode.arity()] {
+            write!(f, " {}", operand)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Instruction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        InstructionSet::builtin().parse_line(s)
+    }
+}
+
+// A 'tgl' that was executed, with the number of steps taken before it. 'change' is the old and the
+// new instruction, or None if the target was outside the program and nothing changed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Toggle {
+    pub cycle: usize,
+    pub pc: usize,
+    pub target: i32,
+    pub change: Option<(Instruction, Instruction)>,
+}
+
+impl fmt::Display for Toggle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "cycle {}: tgl at {} ", self.cycle, self.pc)?;
+        match self.change {
+            Some((old, new)) => write!(f, "turned {} from '{}' into '{}'", self.target, old, new),
+            None => write!(f, "skipped {}, outside the program", self.target),
+        }
+    }
+}
+
+pub fn parse(input: &str) -> Vec<Instruction> {
+    input
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| l.parse().unwrap_or_else(|e| panic!("{}", e)))
+        .collect()
+}
+
+#[derive(Clone)]
+pub struct Machine<W = i32> {
+    pub program: Vec<Instruction>,
+    pub registers: [W; 4],
+    pub pc: i32,
+    pub output: Vec<W>,
+    // Values waiting to be read by 'in'. Reading from an empty queue blocks the machine until
+    // more is added.
+    pub input: VecDeque<W>,
+    // Invalid instructions that were skipped, with the pc they were at
+    pub skipped: Vec<(usize, Instruction)>,
+    // The instruction that overflowed a register, with its pc. The machine stops there.
+    pub overflow: Option<(usize, Instruction)>,
+    // Instructions executed so far. A fused loop counts every instruction it stands for.
+    pub cycles: usize,
+    // Every 'tgl' executed, in order
+    pub toggles: Vec<Toggle>,
+    blocks: Vec<Option<Block>>,
+}
+
+impl<W: Word> Machine<W> {
+    pub fn new(program: Vec<Instruction>, registers: [W; 4]) -> Self {
+        Machine {
+            program,
+            registers,
+            pc: 0,
+            output: Vec::new(),
+            input: VecDeque::new(),
+            skipped: Vec::new(),
+            overflow: None,
+            cycles: 0,
+            toggles: Vec::new(),
+            blocks: Vec::new(),
+        }
+    }
+
+    // Runs recognised add and multiply loops as single steps from now on.
+    pub fn optimize(&mut self) {
+        self.blocks = optimizer::find_blocks(&self.program);
+    }
+
+    fn value(&self, operand: Operand) -> W {
+        match operand {
+            Operand::Register(r) => self.registers[r].clone(),
+            Operand::Value(v) => W::from_i32(v),
+        }
+    }
+
+    // Everything that decides how the program continues. The program is included as 'tgl' can
+    // change it.
+    pub fn state(&self) -> (i32, [W; 4], Vec<Instruction>) {
+        (self.pc, self.registers.clone(), self.program.clone())
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.pc < 0 || self.pc >= self.program.len() as i32 || self.overflow.is_some()
+    }
+
+    // Blocked on an 'in' with nothing in the input queue
+    pub fn is_waiting(&self) -> bool {
+        !self.is_halted()
+            && self.input.is_empty()
+            && matches!(
+                self.program[self.pc as usize],
+                Instruction::In(Operand::Register(_))
+            )
+    }
+
+    // Executes one instruction. Returns false if the program has already halted, the
+    // instruction overflowed or it is waiting for input.
+    pub fn step(&mut self) -> bool {
+        match self.execute() {
+            Some(instructions) => {
+                self.cycles = self.cycles.saturating_add(instructions);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // The number of instructions executed, which is more than one for a fused loop
+    fn execute(&mut self) -> Option<usize> {
+        if self.is_halted() {
+            return None;
+        }
+
+        if let Some(&Some(block)) = self.blocks.get(self.pc as usize) {
+            match block.execute(&mut self.registers) {
+                Execution::Done(instructions) => {
+                    self.pc += block.len() as i32;
+                    return Some(instructions);
+                }
+                Execution::Overflow => {
+                    // Reported at the instruction stepping the target, as if interpreted
+                    let start = self.pc as usize;
+                    let pc = (start..start + block.len())
+                        .find(|&pc| match self.program[pc] {
+                            Instruction::Inc(Operand::Register(r))
+                            | Instruction::Dec(Operand::Register(r)) => r == block.target(),
+                            _ => false,
+                        })
+                        .unwrap_or(start);
+                    self.overflow = Some((pc, self.program[pc]));
+                    return None;
+                }
+                Execution::Interpret => {}
+            }
+        }
+
+        let instruction = self.program[self.pc as usize];
+        let (opcode, operands) = instruction.decode();
+        match opcode.execute(&operands[..opcode.arity()], self) {
+            Flow::Next => {}
+            Flow::Jump(offset) => {
+                self.pc = offset_pc(self.pc, offset);
+                return Some(1);
+            }
+            Flow::Skip => self.skipped.push((self.pc as usize, instruction)),
+            Flow::Overflow => {
+                self.overflow = Some((self.pc as usize, instruction));
+                return None;
+            }
+            Flow::Wait => return None,
+        }
+
+        self.pc += 1;
+        Some(1)
+    }
+
+    pub fn run(&mut self) {
+        while self.step() {}
+    }
+
+    // Like 'run', for answers that are wrong if a register overflowed on the way
+    pub fn run_checked(&mut self) -> Result<(), String> {
+        self.run();
+        match self.overflow {
+            Some((pc, instruction)) => Err(format!("Overflow at {}: {}", pc, instruction)),
+            None => Ok(()),
+        }
+    }
+}
+
+// Offsets too large for the pc leave the program either way
+fn offset_pc(pc: i32, offset: i64) -> i32 {
+    i32::try_from(offset)
+        .ok()
+        .and_then(|offset| pc.checked_add(offset))
+        .unwrap_or(-1)
+}
+
+impl<W: Word> Cpu for Machine<W> {
+    fn get(&self, operand: Operand) -> Option<i64> {
+        self.value(operand).to_i64()
+    }
+
+    fn set(&mut self, register: usize, value: i64) -> bool {
+        let Some(value) = W::from_i64(value) else {
+            return false;
+        };
+        self.registers[register] = value;
+        true
+    }
+
+    fn copy(&mut self, from: Operand, register: usize) {
+        self.registers[register] = self.value(from);
+    }
+
+    fn add(&mut self, register: usize, step: i32) -> bool {
+        let Some(value) = self.registers[register].checked_add(&W::from_i32(step)) else {
+            return false;
+        };
+        self.registers[register] = value;
+        true
+    }
+
+    fn output(&mut self, operand: Operand) {
+        self.output.push(self.value(operand));
+    }
+
+    fn input(&mut self, register: usize) -> bool {
+        let Some(value) = self.input.pop_front() else {
+            return false;
+        };
+        self.registers[register] = value;
+        true
+    }
+
+    fn toggle(&mut self, offset: i64) {
+        let target_idx = offset_pc(self.pc, offset);
+        let mut change = None;
+        if target_idx >= 0 && target_idx < self.program.len() as i32 {
+            let target = &mut self.program[target_idx as usize];
+            change = Some((*target, target.toggled()));
+            *target = target.toggled();
+
+            if !self.blocks.is_empty() {
+                optimizer::update_blocks(&mut self.blocks, &self.program, target_idx as usize);
+            }
+        }
+        self.toggles.push(Toggle {
+            cycle: self.cycles,
+            pc: self.pc as usize,
+            target: target_idx,
+            change,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bigint::BigInt;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse("cpy 41 a\njnz c -2\ntgl b\nout d"),
+            vec![
+                Instruction::Cpy(Operand::Value(41), Operand::Register(0)),
+                Instruction::Jnz(Operand::Register(2), Operand::Value(-2)),
+                Instruction::Tgl(Operand::Register(1)),
+                Instruction::Out(Operand::Register(3)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_display_round_trip() {
+        let input = "cpy -3 b\ninc a\ndec c\njnz 1 d\ntgl c\nout 0\nin d";
+        let listing: Vec<String> = parse(input).iter().map(|i| i.to_string()).collect();
+        assert_eq!(listing.join("\n"), input);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!("mul a b".parse::<Instruction>().is_err());
+        assert!("inc e".parse::<Instruction>().is_err());
+        assert!("cpy a".parse::<Instruction>().is_err());
+    }
+
+    #[test]
+    fn test_toggled() {
+        let (a, one) = (Operand::Register(0), Operand::Value(1));
+        assert_eq!(Instruction::Inc(a).toggled(), Instruction::Dec(a));
+        assert_eq!(Instruction::Dec(a).toggled(), Instruction::Inc(a));
+        assert_eq!(Instruction::Tgl(a).toggled(), Instruction::Inc(a));
+        assert_eq!(Instruction::Out(a).toggled(), Instruction::Inc(a));
+        assert_eq!(Instruction::In(a).toggled(), Instruction::Inc(a));
+        assert_eq!(Instruction::Jnz(one, a).toggled(), Instruction::Cpy(one, a));
+        assert_eq!(Instruction::Cpy(one, a).toggled(), Instruction::Jnz(one, a));
+    }
+
+    #[test]
+    fn test_run() {
+        let mut machine = Machine::new(parse("cpy 3 b\ninc a\ndec b\njnz b -2\nout a"), [0; 4]);
+        machine.run();
+        assert_eq!(machine.registers, [3, 0, 0, 0]);
+        assert_eq!(machine.output, vec![3]);
+        assert!(machine.skipped.is_empty());
+    }
+
+    #[test]
+    fn test_toggled_two_argument_into_literal_is_skipped() {
+        // 'jnz 7 2' becomes 'cpy 7 2', which must not jump nor write anywhere
+        let mut machine = Machine::new(parse("tgl 2\ninc a\njnz 7 2\ninc a"), [0; 4]);
+        machine.run();
+        assert_eq!(machine.registers, [2, 0, 0, 0]);
+        assert_eq!(
+            machine.skipped,
+            vec![(2, Instruction::Cpy(Operand::Value(7), Operand::Value(2)))]
+        );
+    }
+
+    #[test]
+    fn test_toggled_one_argument_into_literal_is_skipped() {
+        // 'tgl 5' becomes 'inc 5'
+        let mut machine = Machine::new(parse("tgl 1\ntgl 5\ninc a"), [0; 4]);
+        machine.run();
+        assert_eq!(machine.registers, [1, 0, 0, 0]);
+        assert_eq!(
+            machine.skipped,
+            vec![(1, Instruction::Inc(Operand::Value(5)))]
+        );
+    }
+
+    #[test]
+    fn test_toggled_into_valid_instruction_runs() {
+        // 'jnz 1 c' becomes 'cpy 1 c' and 'dec a' becomes 'inc a'
+        let mut machine = Machine::new(parse("tgl 2\ntgl 2\njnz 1 c\ndec a"), [0; 4]);
+        machine.run();
+        assert_eq!(machine.registers, [1, 0, 1, 0]);
+        assert!(machine.skipped.is_empty());
+    }
+
+    #[test]
+    fn test_overflow_stops_the_machine() {
+        let program = parse("cpy 2147483646 a\ninc a\ninc a\ninc b");
+
+        let mut machine = Machine::new(program.clone(), [0; 4]);
+        machine.run();
+        assert_eq!(machine.registers, [i32::MAX, 0, 0, 0]);
+        assert_eq!(machine.overflow, Some((2, program[2])));
+        assert!(!machine.step());
+
+        let mut machine = Machine::new(program.clone(), [0; 4]);
+        assert_eq!(
+            machine.run_checked(),
+            Err("Overflow at 2: inc a".to_string())
+        );
+
+        let mut machine = Machine::new(program, [0i64; 4]);
+        machine.run();
+        assert_eq!(machine.registers, [1 << 31, 1, 0, 0]);
+        assert_eq!(machine.overflow, None);
+    }
+
+    #[test]
+    fn test_overflow_in_fused_loop() {
+        // a += 65536 * 32768 overflows
+        let program = parse("cpy 32768 d\ncpy 65536 c\ninc a\ndec c\njnz c -2\ndec d\njnz d -5");
+
+        let mut machine = Machine::new(program.clone(), [0; 4]);
+        machine.optimize();
+        machine.run();
+        assert_eq!(machine.overflow, Some((2, program[2])));
+        assert_eq!(machine.registers[0], 0);
+
+        let mut machine = Machine::new(program, [0i64; 4]);
+        machine.optimize();
+        machine.run();
+        assert_eq!(machine.registers[0], 1 << 31);
+    }
+
+    #[test]
+    fn test_bigint_registers() {
+        // a = b * b, then the same again with the result
+        let square = "cpy b c\ncpy b d\ninc a\ndec d\njnz d -2\ndec c\njnz c -5";
+        let program = parse(&format!("{}\ncpy a b\ncpy 0 a\n{}", square, square));
+        let mut machine = Machine::new(program, [0, 1_000_000, 0, 0].map(BigInt::from));
+        machine.optimize();
+        machine.run();
+        assert_eq!(
+            machine.registers[0].to_string(),
+            "1000000000000000000000000"
+        );
+        // About 3 * 10^24 instructions, more than a usize counts
+        assert_eq!(machine.cycles, usize::MAX);
+    }
+}
@@ -0,0 +1,204 @@
+// Assembunny with labels, comments and named constants:
+//
+//     .const SIGNAL 2730
+//     cpy SIGNAL d      # comment
+//     loop: out d
+//     jnz 1 loop
+//
+// Labels stand for the relative offset to the instruction after them, wherever they are used as
+// an operand. A label may be on a line of its own.
+
+use std::collections::HashMap;
+
+use super::Instruction;
+
+#[derive(Debug, Copy, Clone)]
+enum Name {
+    Const(i32),
+    Label(usize),
+}
+
+fn is_name(s: &str) -> bool {
+    let mut chars = s.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+
+    valid && !matches!(s, "a" | "b" | "c" | "d")
+}
+
+fn define<'a>(
+    names: &mut HashMap<&'a str, Name>,
+    name: &'a str,
+    value: Name,
+) -> Result<(), String> {
+    if !is_name(name) {
+        return Err(format!("Invalid name: {}", name));
+    }
+    if names.insert(name, value).is_some() {
+        return Err(format!("Duplicate name: {}", name));
+    }
+    Ok(())
+}
+
+// Resolves the source into plain Assembunny. Errors give the 1-based line they were found on.
+pub fn assemble(source: &str) -> Result<Vec<Instruction>, String> {
+    let mut names = HashMap::new();
+    // (line, text) of every instruction
+    let mut lines = Vec::new();
+
+    for (idx, line) in source.lines().enumerate() {
+        let error = |e| format!("line {}: {}", idx + 1, e);
+        let mut text = line.split('#').next().unwrap().trim();
+
+        if text.starts_with('.') {
+            let parts: Vec<&str> = text.split_whitespace().collect();
+            let [".const", name, value] = parts[..] else {
+                return Err(error(format!("Unknown directive: {}", text)));
+            };
+            let value = value
+                .parse()
+                .map_err(|_| error(format!("Unknown value: {}", value)))?;
+            define(&mut names, name, Name::Const(value)).map_err(error)?;
+            continue;
+        }
+
+        while let Some((label, rest)) = text.split_once(':') {
+            define(&mut names, label.trim(), Name::Label(lines.len())).map_err(error)?;
+            text = rest.trim();
+        }
+
+        if !text.is_empty() {
+            lines.push((idx + 1, text));
+        }
+    }
+
+    lines
+        .iter()
+        .enumerate()
+        .map(|(pc, &(line, text))| {
+            let error = |e| format!("line {}: {}", line, e);
+
+            let words: Vec<String> = text
+                .split_whitespace()
+                .enumerate()
+                .map(|(i, word)| match names.get(word) {
+                    Some(_) if i == 0 => Ok(word.to_string()),
+                    Some(Name::Const(value)) => Ok(value.to_string()),
+                    Some(&Name::Label(target)) => Ok((target as i32 - pc as i32).to_string()),
+                    None if i > 0 && is_name(word) => Err(format!("Undefined label: {}", word)),
+                    None => Ok(word.to_string()),
+                })
+                .collect::<Result<_, _>>()
+                .map_err(error)?;
+
+            words.join(" ").parse().map_err(error)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembunny::{parse, Operand};
+
+    #[test]
+    fn test_constants_and_comments() {
+        let source = "# Start from the signal
+.const SIGNAL 2730
+.const STEP -2
+
+cpy SIGNAL d   # into d
+inc d";
+
+        assert_eq!(
+            assemble(source),
+            Ok(vec![
+                Instruction::Cpy(Operand::Value(2730), Operand::Register(3)),
+                Instruction::Inc(Operand::Register(3)),
+            ])
+        );
+        assert_eq!(
+            assemble(".const STEP -2\njnz a STEP"),
+            Ok(parse("jnz a -2"))
+        );
+    }
+
+    #[test]
+    fn test_labels() {
+        // The printing part of day 25
+        let source = "start:  cpy d a
+next:   jnz 0 0
+        cpy a b
+        cpy 0 a
+halve:  cpy 2 c
+count:  jnz b odd
+        jnz 1 halved
+odd:    dec b
+        dec c
+        jnz c count
+        inc a
+        jnz 1 halve
+halved: cpy 2 b
+bit:    jnz c 2     # plain offsets still work
+        jnz 1 print
+        dec b
+        dec c
+        jnz 1 bit
+print:
+        jnz 0 0
+        out b
+        jnz a next
+        jnz 1 start";
+
+        assert_eq!(
+            assemble(source),
+            Ok(parse(
+                "cpy d a
+jnz 0 0
+cpy a b
+cpy 0 a
+cpy 2 c
+jnz b 2
+jnz 1 6
+dec b
+dec c
+jnz c -4
+inc a
+jnz 1 -7
+cpy 2 b
+jnz c 2
+jnz 1 4
+dec b
+dec c
+jnz 1 -4
+jnz 0 0
+out b
+jnz a -19
+jnz 1 -21"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_label_at_end() {
+        assert_eq!(
+            assemble("jnz a done\ninc b\ndone:"),
+            Ok(parse("jnz a 2\ninc b"))
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |source| assemble(source).unwrap_err();
+
+        assert_eq!(error("inc a\njnz a lopo"), "line 2: Undefined label: lopo");
+        assert_eq!(error("x: inc a\nx: dec a"), "line 2: Duplicate name: x");
+        assert_eq!(error(".const x 1\nx: dec a"), "line 2: Duplicate name: x");
+        assert_eq!(error("a: inc a"), "line 1: Invalid name: a");
+        assert_eq!(error(".const N ten"), "line 1: Unknown value: ten");
+        assert_eq!(error(".org 1"), "line 1: Unknown directive: .org 1");
+        assert_eq!(error("\n\nmul a b"), "line 3: Unknown op code: mul a b");
+    }
+}
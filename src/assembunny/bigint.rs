@@ -0,0 +1,215 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+use super::word::Word;
+
+// Just enough of an arbitrary-precision integer for the registers: adding, multiplying and
+// printing. The magnitude is stored as base 2^32 digits, least significant first, without leading
+// zeros. Zero has no digits and is never negative.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BigInt {
+    negative: bool,
+    digits: Vec<u32>,
+}
+
+impl BigInt {
+    fn new(negative: bool, mut digits: Vec<u32>) -> Self {
+        while digits.last() == Some(&0) {
+            digits.pop();
+        }
+        BigInt {
+            negative: negative && !digits.is_empty(),
+            digits,
+        }
+    }
+}
+
+impl From<i64> for BigInt {
+    fn from(value: i64) -> Self {
+        let magnitude = value.unsigned_abs();
+        BigInt::new(value < 0, vec![magnitude as u32, (magnitude >> 32) as u32])
+    }
+}
+
+fn compare(x: &[u32], y: &[u32]) -> Ordering {
+    x.len()
+        .cmp(&y.len())
+        .then_with(|| x.iter().rev().cmp(y.iter().rev()))
+}
+
+fn add(x: &[u32], y: &[u32]) -> Vec<u32> {
+    let mut sum = Vec::with_capacity(x.len().max(y.len()) + 1);
+    let mut carry = 0;
+    for i in 0..x.len().max(y.len()) {
+        let digit = *x.get(i).unwrap_or(&0) as u64 + *y.get(i).unwrap_or(&0) as u64 + carry;
+        sum.push(digit as u32);
+        carry = digit >> 32;
+    }
+    sum.push(carry as u32);
+    sum
+}
+
+// x - y, with x at least as large as y
+fn sub(x: &[u32], y: &[u32]) -> Vec<u32> {
+    let mut difference = Vec::with_capacity(x.len());
+    let mut borrow = 0;
+    for (i, &digit) in x.iter().enumerate() {
+        let (digit, under_x) = digit.overflowing_sub(*y.get(i).unwrap_or(&0));
+        let (digit, under_borrow) = digit.overflowing_sub(borrow);
+        difference.push(digit);
+        borrow = (under_x || under_borrow) as u32;
+    }
+    difference
+}
+
+fn mul(x: &[u32], y: &[u32]) -> Vec<u32> {
+    let mut product = vec![0; x.len() + y.len()];
+    for (i, &x_digit) in x.iter().enumerate() {
+        let mut carry = 0;
+        for (j, &y_digit) in y.iter().enumerate() {
+            let digit = product[i + j] as u64 + x_digit as u64 * y_digit as u64 + carry;
+            product[i + j] = digit as u32;
+            carry = digit >> 32;
+        }
+        product[i + y.len()] = carry as u32;
+    }
+    product
+}
+
+impl Word for BigInt {
+    fn from_i32(value: i32) -> Self {
+        BigInt::from(value as i64)
+    }
+
+    fn from_i64(value: i64) -> Option<Self> {
+        Some(BigInt::from(value))
+    }
+
+    fn to_i64(&self) -> Option<i64> {
+        let magnitude = match self.digits[..] {
+            [] => 0,
+            [low] => low as i128,
+            [low, high] => ((high as i128) << 32) | low as i128,
+            _ => return None,
+        };
+        i64::try_from(if self.negative { -magnitude } else { magnitude }).ok()
+    }
+
+    fn to_i32(&self) -> Option<i32> {
+        match self.digits[..] {
+            [] => Some(0),
+            [digit] if self.negative => i32::try_from(-(digit as i64)).ok(),
+            [digit] => i32::try_from(digit).ok(),
+            _ => None,
+        }
+    }
+
+    fn to_usize(&self) -> Option<usize> {
+        match (self.negative, &self.digits[..]) {
+            (true, _) => None,
+            (false, []) => Some(0),
+            (false, [low]) => usize::try_from(*low).ok(),
+            (false, [low, high]) => usize::try_from(((*high as u64) << 32) | *low as u64).ok(),
+            _ => None,
+        }
+    }
+
+    fn checked_add(&self, other: &Self) -> Option<Self> {
+        if self.negative == other.negative {
+            return Some(BigInt::new(self.negative, add(&self.digits, &other.digits)));
+        }
+
+        Some(match compare(&self.digits, &other.digits) {
+            Ordering::Less => BigInt::new(other.negative, sub(&other.digits, &self.digits)),
+            _ => BigInt::new(self.negative, sub(&self.digits, &other.digits)),
+        })
+    }
+
+    fn checked_mul(&self, other: &Self) -> Option<Self> {
+        Some(BigInt::new(
+            self.negative != other.negative,
+            mul(&self.digits, &other.digits),
+        ))
+    }
+
+    fn is_positive(&self) -> bool {
+        !self.negative && !self.digits.is_empty()
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Split into base 10^9 chunks by dividing the magnitude over and over, least significant
+        // chunk first
+        let mut chunks = Vec::new();
+        let mut magnitude = self.digits.clone();
+        while !magnitude.is_empty() {
+            let mut remainder = 0;
+            for digit in magnitude.iter_mut().rev() {
+                let value = (remainder << 32) | *digit as u64;
+                *digit = (value / 1_000_000_000) as u32;
+                remainder = value % 1_000_000_000;
+            }
+            chunks.push(remainder);
+            while magnitude.last() == Some(&0) {
+                magnitude.pop();
+            }
+        }
+
+        if self.negative {
+            write!(f, "-")?;
+        }
+        match chunks.split_last() {
+            None => write!(f, "0"),
+            Some((most_significant, rest)) => {
+                write!(f, "{}", most_significant)?;
+                rest.iter()
+                    .rev()
+                    .try_for_each(|chunk| write!(f, "{:09}", chunk))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn big(value: i64) -> BigInt {
+        BigInt::from(value)
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let sum = |x: i64, y: i64| big(x).checked_add(&big(y)).unwrap();
+        assert_eq!(sum(i32::MAX as i64, 1), big(1 << 31));
+        assert_eq!(sum(5, -7), big(-2));
+        assert_eq!(sum(-5, 7), big(2));
+        assert_eq!(sum(-5, 5), big(0));
+        assert_eq!(sum(1 << 32, -1), big(u32::MAX as i64));
+
+        let product = big(-123_456_789).checked_mul(&big(987_654_321)).unwrap();
+        assert_eq!(product, big(-121_932_631_112_635_269));
+        assert_eq!(big(0).checked_mul(&big(-3)).unwrap(), big(0));
+    }
+
+    #[test]
+    fn test_conversions() {
+        assert_eq!(big(-42).to_i32(), Some(-42));
+        assert_eq!(big(i32::MIN as i64).to_i32(), Some(i32::MIN));
+        assert_eq!(big(1 << 31).to_i32(), None);
+        assert_eq!(big(i64::MIN).to_i64(), Some(i64::MIN));
+        assert_eq!(big(i64::MAX).checked_add(&big(1)).unwrap().to_i64(), None);
+        assert!(big(1).is_positive());
+        assert!(!big(0).is_positive());
+        assert!(big(0).is_zero());
+    }
+
+    #[test]
+    fn test_display() {
+        let factorial = (1..=30).fold(big(1), |product, n| product.checked_mul(&big(n)).unwrap());
+        assert_eq!(factorial.to_string(), "265252859812191058636308480000000");
+        assert_eq!(big(-1_000_000_007).to_string(), "-1000000007");
+        assert_eq!(big(0).to_string(), "0");
+    }
+}
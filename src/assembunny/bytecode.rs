@@ -0,0 +1,405 @@
+use super::optimizer::{self, Block};
+use super::word::Word;
+use super::{Instruction, Machine, Operand};
+
+// Layout, with all numbers little-endian:
+//   magic "BUNY", version byte, optimized flag byte
+//   instruction count (u32), then per instruction an opcode byte and its operands
+//   fused loop count (u32), then per loop its start pc (u32), a kind byte and its fields
+// An operand is a tag byte followed by a register index byte or an i32 immediate. Steps of fused
+// loops are a single signed byte. The fused loops must be exactly the ones the optimizer finds in
+// the program, and there are none unless it is optimized. Only the built-in opcodes have bytecode.
+const MAGIC: &[u8; 4] = b"BUNY";
+const VERSION: u8 = 2;
+
+const REGISTER: u8 = 0;
+const IMMEDIATE: u8 = 1;
+
+const ADD: u8 = 0;
+const MUL: u8 = 1;
+
+fn opcode(instruction: Instruction) -> Result<(u8, Vec<Operand>), String> {
+    Ok(match instruction {
+        Instruction::Cpy(x, y) => (0, vec![x, y]),
+        Instruction::Inc(x) => (1, vec![x]),
+        Instruction::Dec(x) => (2, vec![x]),
+        Instruction::Jnz(x, y) => (3, vec![x, y]),
+        Instruction::Tgl(x) => (4, vec![x]),
+        Instruction::Out(x) => (5, vec![x]),
+        Instruction::In(x) => (6, vec![x]),
+        Instruction::Custom(_) => return Err(format!("No bytecode for {}", instruction)),
+    })
+}
+
+fn put_operand(bytes: &mut Vec<u8>, operand: Operand) {
+    match operand {
+        Operand::Register(r) => bytes.extend([REGISTER, r as u8]),
+        Operand::Value(v) => {
+            bytes.push(IMMEDIATE);
+            bytes.extend(v.to_le_bytes());
+        }
+    }
+}
+
+// 'blocks' is empty for a program that is not optimized, as in 'Machine'
+pub fn encode(program: &[Instruction], blocks: &[Option<Block>]) -> Result<Vec<u8>, String> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.push(VERSION);
+    bytes.push(!blocks.is_empty() as u8);
+
+    bytes.extend((program.len() as u32).to_le_bytes());
+    for &instruction in program {
+        let (opcode, operands) = opcode(instruction)?;
+        bytes.push(opcode);
+        for operand in operands {
+            put_operand(&mut bytes, operand);
+        }
+    }
+
+    let fused: Vec<_> = blocks
+        .iter()
+        .enumerate()
+        .filter_map(|(pc, block)| block.map(|block| (pc, block)))
+        .collect();
+    bytes.extend((fused.len() as u32).to_le_bytes());
+    for (pc, block) in fused {
+        bytes.extend((pc as u32).to_le_bytes());
+        match block {
+            Block::Add {
+                target,
+                target_step,
+                counter,
+                counter_step,
+            } => bytes.extend([
+                ADD,
+                target as u8,
+                target_step as u8,
+                counter as u8,
+                counter_step as u8,
+            ]),
+            Block::Mul {
+                source,
+                target,
+                target_step,
+                inner,
+                inner_step,
+                outer,
+                outer_step,
+            } => {
+                bytes.push(MUL);
+                put_operand(&mut bytes, source);
+                bytes.extend([
+                    target as u8,
+                    target_step as u8,
+                    inner as u8,
+                    inner_step as u8,
+                    outer as u8,
+                    outer_step as u8,
+                ]);
+            }
+        }
+    }
+
+    Ok(bytes)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> Result<&[u8], String> {
+        let taken = self
+            .bytes
+            .get(self.pos..self.pos + n)
+            .ok_or("Unexpected end of bytecode")?;
+        self.pos += n;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn register(&mut self) -> Result<usize, String> {
+        match self.byte()? {
+            r @ 0..=3 => Ok(r as usize),
+            r => Err(format!("Unknown register: {}", r)),
+        }
+    }
+
+    fn step(&mut self) -> Result<i32, String> {
+        match self.byte()? as i8 {
+            step @ (-1 | 1) => Ok(step as i32),
+            step => Err(format!("Unknown loop step: {}", step)),
+        }
+    }
+
+    fn operand(&mut self) -> Result<Operand, String> {
+        match self.byte()? {
+            REGISTER => Ok(Operand::Register(self.register()?)),
+            IMMEDIATE => Ok(Operand::Value(i32::from_le_bytes(
+                self.take(4)?.try_into().unwrap(),
+            ))),
+            tag => Err(format!("Unknown operand tag: {}", tag)),
+        }
+    }
+
+    fn instruction(&mut self) -> Result<Instruction, String> {
+        Ok(match self.byte()? {
+            0 => Instruction::Cpy(self.operand()?, self.operand()?),
+            1 => Instruction::Inc(self.operand()?),
+            2 => Instruction::Dec(self.operand()?),
+            3 => Instruction::Jnz(self.operand()?, self.operand()?),
+            4 => Instruction::Tgl(self.operand()?),
+            5 => Instruction::Out(self.operand()?),
+            6 => Instruction::In(self.operand()?),
+            opcode => return Err(format!("Unknown opcode: {}", opcode)),
+        })
+    }
+
+    fn block(&mut self) -> Result<Block, String> {
+        Ok(match self.byte()? {
+            ADD => Block::Add {
+                target: self.register()?,
+                target_step: self.step()?,
+                counter: self.register()?,
+                counter_step: self.step()?,
+            },
+            MUL => Block::Mul {
+                source: self.operand()?,
+                target: self.register()?,
+                target_step: self.step()?,
+                inner: self.register()?,
+                inner_step: self.step()?,
+                outer: self.register()?,
+                outer_step: self.step()?,
+            },
+            kind => return Err(format!("Unknown loop kind: {}", kind)),
+        })
+    }
+}
+
+// The program and its fused loops. The loops are empty if the program was not optimized.
+pub fn decode(bytes: &[u8]) -> Result<(Vec<Instruction>, Vec<Option<Block>>), String> {
+    let mut reader = Reader { bytes, pos: 0 };
+
+    if reader.take(MAGIC.len()).ok() != Some(MAGIC.as_slice()) {
+        return Err("Not Assembunny bytecode".to_string());
+    }
+    let version = reader.byte()?;
+    if version != VERSION {
+        return Err(format!("Unsupported version: {}", version));
+    }
+    let optimized = match reader.byte()? {
+        0 => false,
+        1 => true,
+        flag => return Err(format!("Unknown optimized flag: {}", flag)),
+    };
+
+    let program = (0..reader.u32()?)
+        .map(|_| reader.instruction())
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut blocks = vec![None; program.len()];
+    for _ in 0..reader.u32()? {
+        let pc = reader.u32()? as usize;
+        let block = reader.block()?;
+        if pc + block.len() > program.len() {
+            return Err(format!("Fused loop at {} is outside the program", pc));
+        }
+        blocks[pc] = Some(block);
+    }
+
+    if reader.pos != bytes.len() {
+        return Err("Trailing bytes after the program".to_string());
+    }
+
+    // A loop that does not match its instructions would compute something else than the program
+    let expected = if optimized {
+        optimizer::find_blocks(&program)
+    } else {
+        vec![None; program.len()]
+    };
+    if let Some(pc) = (0..program.len()).find(|&pc| blocks[pc] != expected[pc]) {
+        return Err(format!("Fused loop at {} does not match the program", pc));
+    }
+
+    Ok((program, if optimized { blocks } else { Vec::new() }))
+}
+
+impl<W: Word> Machine<W> {
+    // The current program, toggles included, with the fused loops if it was optimized
+    pub fn to_bytecode(&self) -> Result<Vec<u8>, String> {
+        encode(&self.program, &self.blocks)
+    }
+
+    pub fn from_bytecode(bytes: &[u8], registers: [W; 4]) -> Result<Self, String> {
+        let (program, blocks) = decode(bytes)?;
+        let mut machine = Machine::new(program, registers);
+        machine.blocks = blocks;
+        Ok(machine)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembunny::parse;
+
+    // The examples of days 12 and 23, and the printing part of day 25
+    static EXAMPLES: [&str; 3] = [
+        "cpy 41 a\ninc a\ninc a\ndec a\njnz a 2\ndec a",
+        "cpy 2 a\ntgl a\ntgl a\ntgl a\ncpy 1 a\ndec a\ndec a",
+        "cpy a b
+cpy 0 a
+cpy 2 c
+jnz b 2
+jnz 1 6
+dec b
+dec c
+jnz c -4
+inc a
+jnz 1 -7
+cpy 2 b
+jnz c 2
+jnz 1 4
+dec b
+dec c
+jnz 1 -4
+out b
+jnz a -17",
+    ];
+
+    #[test]
+    fn test_round_trip() {
+        for example in EXAMPLES {
+            let program = parse(example);
+            let bytes = encode(&program, &[]).unwrap();
+            assert_eq!(decode(&bytes), Ok((program, vec![])));
+        }
+    }
+
+    #[test]
+    fn test_encoding() {
+        let bytes = encode(&parse("cpy -2 a\nout d"), &[]).unwrap();
+        assert_eq!(
+            bytes,
+            [
+                b"BUNY".as_slice(),
+                &[2, 0, 2, 0, 0, 0],
+                &[0, 1, 0xfe, 0xff, 0xff, 0xff, 0, 0],
+                &[5, 0, 3],
+                &[0, 0, 0, 0],
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn test_optimized_round_trip() {
+        let program = parse("cpy 3 b\ncpy 4 c\ncpy b d\ninc a\ndec d\njnz d -2\ndec c\njnz c -5");
+        let mut machine = Machine::new(program, [0; 4]);
+        machine.optimize();
+
+        let mut loaded = Machine::from_bytecode(&machine.to_bytecode().unwrap(), [0; 4]).unwrap();
+        assert_eq!(loaded.program, machine.program);
+        assert_eq!(loaded.blocks, machine.blocks);
+
+        loaded.run();
+        assert_eq!(loaded.registers, [12, 3, 0, 0]);
+
+        // Still optimized without any loops, so loops created by 'tgl' are fused later
+        let mut machine = Machine::new(parse("tgl 3\ninc a\ndec b\ncpy b -2"), [0; 4]);
+        machine.optimize();
+        let mut loaded =
+            Machine::from_bytecode(&machine.to_bytecode().unwrap(), [0, 0, 0, 0]).unwrap();
+        assert_eq!(loaded.blocks, vec![None; 4]);
+        loaded.step();
+        assert!(loaded.blocks[1].is_some());
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let bytes = encode(&parse(EXAMPLES[0]), &[]).unwrap();
+
+        assert_eq!(decode(b"BUNX"), Err("Not Assembunny bytecode".to_string()));
+        assert_eq!(
+            decode(&[b"BUNY".as_slice(), &[1]].concat()),
+            Err("Unsupported version: 1".to_string())
+        );
+        assert_eq!(
+            decode(&[b"BUNY".as_slice(), &[2, 2]].concat()),
+            Err("Unknown optimized flag: 2".to_string())
+        );
+        assert_eq!(
+            decode(&bytes[..bytes.len() - 1]),
+            Err("Unexpected end of bytecode".to_string())
+        );
+        assert_eq!(
+            decode(&[bytes.as_slice(), &[0]].concat()),
+            Err("Trailing bytes after the program".to_string())
+        );
+
+        let mut bad_register = bytes.clone();
+        // The register of 'cpy 41 a'
+        bad_register[17] = 4;
+        assert_eq!(
+            decode(&bad_register),
+            Err("Unknown register: 4".to_string())
+        );
+    }
+
+    #[test]
+    fn test_mismatched_loops() {
+        // 'inc a; dec b; jnz b -2' adds b to a
+        let program = parse("cpy 3 b\ninc a\ndec b\njnz b -2");
+        let blocks = optimizer::find_blocks(&program);
+        let Some(Block::Add {
+            target_step,
+            counter,
+            counter_step,
+            ..
+        }) = blocks[1]
+        else {
+            panic!("Expected an add at 1, got {:?}", blocks[1]);
+        };
+
+        let mut wrong = blocks.clone();
+        wrong[1] = Some(Block::Add {
+            target: 2,
+            target_step,
+            counter,
+            counter_step,
+        });
+        assert_eq!(
+            decode(&encode(&program, &wrong).unwrap()),
+            Err("Fused loop at 1 does not match the program".to_string())
+        );
+
+        let mut missing = blocks.clone();
+        missing[1] = None;
+        assert_eq!(
+            decode(&encode(&program, &missing).unwrap()),
+            Err("Fused loop at 1 does not match the program".to_string())
+        );
+
+        // Loops in a program that is not optimized
+        let mut bytes = encode(&program, &blocks).unwrap();
+        bytes[5] = 0;
+        assert_eq!(
+            decode(&bytes),
+            Err("Fused loop at 1 does not match the program".to_string())
+        );
+
+        let mut machine =
+            Machine::from_bytecode(&encode(&program, &blocks).unwrap(), [0; 4]).unwrap();
+        machine.run();
+        assert_eq!(machine.registers, [3, 0, 0, 0]);
+    }
+}
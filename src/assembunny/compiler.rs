@@ -0,0 +1,678 @@
+// A small language that compiles to Assembunny:
+//
+//     b = 5
+//     while b != 0 {
+//         a = a + 2 * b
+//         out a
+//         b = b - 1
+//     }
+//
+// Variables are the registers a-d. Expressions are sums of terms, and a term is a product of at
+// most two variables and any number of constants. The compiled code adds and multiplies with
+// counting loops, so the variables read by those loops must not be negative.
+
+use std::iter::Peekable;
+use std::vec::IntoIter;
+
+use super::{Instruction, Operand};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Atom {
+    Var(usize),
+    Const(i32),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Term {
+    pub sign: i32,
+    pub factors: Vec<Atom>,
+}
+
+pub type Expr = Vec<Term>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stmt {
+    Assign(usize, Expr),
+    While(usize, Vec<Stmt>),
+    Out(Expr),
+}
+
+type Tokens = Peekable<IntoIter<String>>;
+
+fn tokenize(source: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' | ';' => tokens.push(";".to_string()),
+            '!' if chars.peek() == Some(&'=') => {
+                chars.next();
+                tokens.push("!=".to_string());
+            }
+            c if c.is_whitespace() => {}
+            c if c.is_ascii_alphanumeric() => {
+                let mut token = c.to_string();
+                while let Some(&c) = chars.peek().filter(|c| c.is_ascii_alphanumeric()) {
+                    token.push(c);
+                    chars.next();
+                }
+                tokens.push(token);
+            }
+            c => tokens.push(c.to_string()),
+        }
+    }
+
+    tokens
+}
+
+fn expect(tokens: &mut Tokens, expected: &str) -> Result<(), String> {
+    match tokens.next() {
+        Some(token) if token == expected => Ok(()),
+        Some(token) => Err(format!("Expected '{}', found '{}'", expected, token)),
+        None => Err(format!("Expected '{}', found end of input", expected)),
+    }
+}
+
+fn parse_var(tokens: &mut Tokens) -> Result<usize, String> {
+    match tokens.next().as_deref().map(str::parse) {
+        Some(Ok(Operand::Register(r))) => Ok(r),
+        _ => Err("Expected a variable a-d".to_string()),
+    }
+}
+
+fn parse_atom(tokens: &mut Tokens) -> Result<Atom, String> {
+    let token = tokens.next().ok_or("Unexpected end of input")?;
+
+    match token.parse::<Operand>() {
+        Ok(Operand::Register(r)) => Ok(Atom::Var(r)),
+        Ok(Operand::Value(v)) => Ok(Atom::Const(v)),
+        Err(_) => Err(format!("Unknown value: {}", token)),
+    }
+}
+
+fn parse_expr(tokens: &mut Tokens) -> Result<Expr, String> {
+    let mut expr = Vec::new();
+    let mut sign = if tokens.next_if(|t| t == "-").is_some() {
+        -1
+    } else {
+        1
+    };
+
+    loop {
+        let mut factors = vec![parse_atom(tokens)?];
+        while tokens.next_if(|t| t == "*").is_some() {
+            factors.push(parse_atom(tokens)?);
+        }
+        expr.push(Term { sign, factors });
+
+        sign = match tokens.peek().map(String::as_str) {
+            Some("+") => 1,
+            Some("-") => -1,
+            _ => return Ok(expr),
+        };
+        tokens.next();
+    }
+}
+
+fn parse_block(tokens: &mut Tokens, nested: bool) -> Result<Vec<Stmt>, String> {
+    let mut stmts = Vec::new();
+
+    loop {
+        let Some(token) = tokens.peek().cloned() else {
+            return if nested {
+                Err("Missing '}'".to_string())
+            } else {
+                Ok(stmts)
+            };
+        };
+
+        match token.as_str() {
+            ";" => {
+                tokens.next();
+            }
+            "}" if nested => {
+                tokens.next();
+                return Ok(stmts);
+            }
+            "while" => {
+                tokens.next();
+                let var = parse_var(tokens)?;
+                expect(tokens, "!=")?;
+                expect(tokens, "0")?;
+                expect(tokens, "{")?;
+                stmts.push(Stmt::While(var, parse_block(tokens, true)?));
+            }
+            "out" => {
+                tokens.next();
+                stmts.push(Stmt::Out(parse_expr(tokens)?));
+            }
+            _ => {
+                let var = parse_var(tokens)?;
+                expect(tokens, "=")?;
+                stmts.push(Stmt::Assign(var, parse_expr(tokens)?));
+            }
+        }
+    }
+}
+
+pub fn parse_source(source: &str) -> Result<Vec<Stmt>, String> {
+    parse_block(&mut tokenize(source).into_iter().peekable(), false)
+}
+
+// A term with its constants folded into one coefficient.
+struct Product {
+    coefficient: i32,
+    vars: Vec<usize>,
+}
+
+fn product(term: &Term) -> Product {
+    let mut product = Product {
+        coefficient: term.sign,
+        vars: Vec::new(),
+    };
+    for factor in &term.factors {
+        match factor {
+            Atom::Const(v) => product.coefficient *= v,
+            Atom::Var(r) => product.vars.push(*r),
+        }
+    }
+    product
+}
+
+// How an assignment is compiled. 'in_place' is set for 'x = x + ...', where the other terms are
+// added straight into x.
+struct Plan {
+    in_place: bool,
+    constant: i32,
+    products: Vec<Product>,
+}
+
+fn plan(target: Option<usize>, expr: &Expr) -> Plan {
+    let mut plan = Plan {
+        in_place: false,
+        constant: 0,
+        products: Vec::new(),
+    };
+
+    for term in expr {
+        let product = product(term);
+        match product.vars[..] {
+            [] => plan.constant += product.coefficient,
+            [r] if Some(r) == target && product.coefficient == 1 && !plan.in_place => {
+                plan.in_place = true;
+            }
+            _ if product.coefficient == 0 => {}
+            _ => plan.products.push(product),
+        }
+    }
+
+    plan
+}
+
+// A single variable or a constant, which needs no arithmetic loops.
+fn as_atom(expr: &Expr) -> Option<Atom> {
+    if let [Term { sign: 1, factors }] = &expr[..] {
+        if factors.len() == 1 {
+            return Some(factors[0]);
+        }
+    }
+
+    let plan = plan(None, expr);
+    plan.products
+        .is_empty()
+        .then_some(Atom::Const(plan.constant))
+}
+
+// Variables that the compiled code counts down to zero, and so must not be negative.
+fn loop_operands(target: Option<usize>, expr: &Expr) -> Vec<usize> {
+    if as_atom(expr).is_some() {
+        return Vec::new();
+    }
+
+    plan(target, expr)
+        .products
+        .iter()
+        .flat_map(|p| p.vars.clone())
+        .collect()
+}
+
+fn value(atom: Atom, registers: &[i32; 4]) -> i32 {
+    match atom {
+        Atom::Var(r) => registers[r],
+        Atom::Const(v) => v,
+    }
+}
+
+fn evaluate(target: Option<usize>, expr: &Expr, registers: &[i32; 4]) -> Result<i32, String> {
+    if let Some(r) = loop_operands(target, expr)
+        .into_iter()
+        .find(|&r| registers[r] < 0)
+    {
+        return Err(format!("Negative loop operand: {}", Operand::Register(r)));
+    }
+
+    Ok(expr
+        .iter()
+        .map(|term| {
+            term.sign
+                * term
+                    .factors
+                    .iter()
+                    .map(|&f| value(f, registers))
+                    .product::<i32>()
+        })
+        .sum())
+}
+
+// Runs the source program directly, as the reference for what the compiled program must do.
+pub fn interpret(
+    stmts: &[Stmt],
+    registers: &mut [i32; 4],
+    output: &mut Vec<i32>,
+) -> Result<(), String> {
+    for stmt in stmts {
+        match stmt {
+            Stmt::Assign(r, expr) => registers[*r] = evaluate(Some(*r), expr, registers)?,
+            Stmt::While(r, body) => {
+                while registers[*r] != 0 {
+                    interpret(body, registers, output)?;
+                }
+            }
+            Stmt::Out(expr) => output.push(evaluate(None, expr, registers)?),
+        }
+    }
+
+    Ok(())
+}
+
+fn used_vars(stmts: &[Stmt], used: &mut [bool; 4]) {
+    let mark_expr = |expr: &Expr, used: &mut [bool; 4]| {
+        for factor in expr.iter().flat_map(|t| &t.factors) {
+            if let Atom::Var(r) = factor {
+                used[*r] = true;
+            }
+        }
+    };
+
+    for stmt in stmts {
+        match stmt {
+            Stmt::Assign(r, expr) => {
+                used[*r] = true;
+                mark_expr(expr, used);
+            }
+            Stmt::While(r, body) => {
+                used[*r] = true;
+                used_vars(body, used);
+            }
+            Stmt::Out(expr) => mark_expr(expr, used),
+        }
+    }
+}
+
+struct Compiler {
+    code: Vec<Instruction>,
+    // Registers the source never uses, free for intermediate values
+    temps: Vec<usize>,
+    // Number of temps held by an expression being computed
+    reserved: usize,
+}
+
+fn reg(r: usize) -> Operand {
+    Operand::Register(r)
+}
+
+impl Compiler {
+    fn temp(&self, n: usize) -> Result<usize, String> {
+        self.temps
+            .get(self.reserved + n)
+            .copied()
+            .ok_or_else(|| "Not enough free registers for intermediate values".to_string())
+    }
+
+    // Skips the rest of the code in 'body' if 'x' is zero.
+    fn guarded(x: Operand, mut body: Vec<Instruction>) -> Vec<Instruction> {
+        if let Operand::Register(_) = x {
+            let skip = body.len() as i32 + 1;
+            body.splice(
+                0..0,
+                [
+                    Instruction::Jnz(x, Operand::Value(2)),
+                    Instruction::Jnz(Operand::Value(1), Operand::Value(skip)),
+                ],
+            );
+        }
+        body
+    }
+
+    // acc += coefficient * vars, all zero-checked so the loops never start from zero.
+    fn add_product(&mut self, acc: usize, product: &Product) -> Result<(), String> {
+        let step = if product.coefficient > 0 {
+            Instruction::Inc(reg(acc))
+        } else {
+            Instruction::Dec(reg(acc))
+        };
+        let (t, o) = (self.temp(0)?, self.temp(1));
+
+        let code = match (&product.vars[..], product.coefficient.abs()) {
+            ([x], 1) => Self::guarded(
+                reg(*x),
+                vec![
+                    Instruction::Cpy(reg(*x), reg(t)),
+                    step,
+                    Instruction::Dec(reg(t)),
+                    Instruction::Jnz(reg(t), Operand::Value(-2)),
+                ],
+            ),
+            ([x], n) => Self::multiply(reg(*x), Operand::Value(n), step, t, o?),
+            ([x, y], 1) => Self::multiply(reg(*x), reg(*y), step, t, o?),
+            _ => return Err("Unsupported product, use at most two variables".to_string()),
+        };
+
+        self.code.extend(code);
+        Ok(())
+    }
+
+    fn multiply(x: Operand, y: Operand, step: Instruction, t: usize, o: usize) -> Vec<Instruction> {
+        let body = vec![
+            Instruction::Cpy(y, reg(o)),
+            Instruction::Cpy(x, reg(t)),
+            step,
+            Instruction::Dec(reg(t)),
+            Instruction::Jnz(reg(t), Operand::Value(-2)),
+            Instruction::Dec(reg(o)),
+            Instruction::Jnz(reg(o), Operand::Value(-5)),
+        ];
+        Self::guarded(x, Self::guarded(y, body))
+    }
+
+    // Adds a constant with single steps if it is small, or a counting loop otherwise.
+    fn add_constant(&mut self, acc: usize, constant: i32) -> Result<(), String> {
+        let step = if constant > 0 {
+            Instruction::Inc(reg(acc))
+        } else {
+            Instruction::Dec(reg(acc))
+        };
+
+        if constant.abs() <= 3 {
+            self.code.extend((0..constant.abs()).map(|_| step));
+        } else {
+            let t = self.temp(0)?;
+            self.code.extend([
+                Instruction::Cpy(Operand::Value(constant.abs()), reg(t)),
+                step,
+                Instruction::Dec(reg(t)),
+                Instruction::Jnz(reg(t), Operand::Value(-2)),
+            ]);
+        }
+
+        Ok(())
+    }
+
+    // Computes 'expr' into 'target', or into a free register if there is no target. Returns the
+    // register holding the value.
+    fn expr(&mut self, target: Option<usize>, expr: &Expr) -> Result<usize, String> {
+        if let (Some(target), Some(atom)) = (target, as_atom(expr)) {
+            let x = match atom {
+                Atom::Var(r) => reg(r),
+                Atom::Const(v) => Operand::Value(v),
+            };
+            self.code.push(Instruction::Cpy(x, reg(target)));
+            return Ok(target);
+        }
+
+        let plan = plan(target, expr);
+        let reads_target = target.is_some_and(|target| {
+            plan.products
+                .iter()
+                .any(|product| product.vars.contains(&target))
+        });
+
+        let acc = match target {
+            Some(target) if !reads_target => target,
+            _ => {
+                let acc = self.temp(0)?;
+                self.reserved += 1;
+                if plan.in_place {
+                    self.code
+                        .push(Instruction::Cpy(reg(target.unwrap()), reg(acc)));
+                }
+                acc
+            }
+        };
+
+        if plan.in_place {
+            self.add_constant(acc, plan.constant)?;
+        } else {
+            self.code
+                .push(Instruction::Cpy(Operand::Value(plan.constant), reg(acc)));
+        }
+        for product in &plan.products {
+            self.add_product(acc, product)?;
+        }
+
+        if target != Some(acc) {
+            self.reserved -= 1;
+            if let Some(target) = target {
+                self.code.push(Instruction::Cpy(reg(acc), reg(target)));
+            }
+        }
+
+        Ok(acc)
+    }
+
+    fn block(&mut self, stmts: &[Stmt]) -> Result<(), String> {
+        for stmt in stmts {
+            match stmt {
+                Stmt::Assign(r, expr) => {
+                    self.expr(Some(*r), expr)?;
+                }
+                Stmt::While(r, body) => {
+                    let start = self.code.len();
+                    self.code.push(Instruction::Jnz(reg(*r), Operand::Value(2)));
+                    self.code
+                        .push(Instruction::Jnz(Operand::Value(1), Operand::Value(0)));
+                    self.block(body)?;
+
+                    let len = (self.code.len() - start) as i32;
+                    self.code
+                        .push(Instruction::Jnz(Operand::Value(1), Operand::Value(-len)));
+                    self.code[start + 1] = Instruction::Jnz(Operand::Value(1), Operand::Value(len));
+                }
+                Stmt::Out(expr) => match as_atom(expr) {
+                    Some(Atom::Var(r)) => self.code.push(Instruction::Out(reg(r))),
+                    Some(Atom::Const(v)) => self.code.push(Instruction::Out(Operand::Value(v))),
+                    None => {
+                        let r = self.expr(None, expr)?;
+                        self.code.push(Instruction::Out(reg(r)));
+                    }
+                },
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub fn compile(stmts: &[Stmt]) -> Result<Vec<Instruction>, String> {
+    let mut used = [false; 4];
+    used_vars(stmts, &mut used);
+
+    let mut compiler = Compiler {
+        code: Vec::new(),
+        temps: (0..4).filter(|&r| !used[r]).collect(),
+        reserved: 0,
+    };
+    compiler.block(stmts)?;
+
+    Ok(compiler.code)
+}
+
+// Compiles source text into Assembunny text.
+pub fn compile_source(source: &str) -> Result<String, String> {
+    let listing: Vec<String> = compile(&parse_source(source)?)?
+        .iter()
+        .map(|i| i.to_string())
+        .collect();
+
+    Ok(listing.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembunny::{parse, Machine};
+
+    // Runs the source both directly and compiled, and checks that the variables it uses and
+    // the output agree.
+    fn round_trip(source: &str, registers: [i32; 4]) -> ([i32; 4], Vec<i32>) {
+        let stmts = parse_source(source).unwrap();
+
+        let mut expected = registers;
+        let mut expected_output = Vec::new();
+        interpret(&stmts, &mut expected, &mut expected_output).unwrap();
+
+        let mut machine = Machine::new(parse(&compile_source(source).unwrap()), registers);
+        machine.optimize();
+        machine.run();
+
+        let mut used = [false; 4];
+        used_vars(&stmts, &mut used);
+        for r in (0..4).filter(|&r| used[r]) {
+            assert_eq!(machine.registers[r], expected[r], "register {}", r);
+        }
+        assert_eq!(machine.output, expected_output);
+        assert!(machine.skipped.is_empty());
+
+        (expected, expected_output)
+    }
+
+    #[test]
+    fn test_parse_source() {
+        assert_eq!(
+            parse_source("a = b * 2 - 3\nwhile a != 0 { out a; a = a - 1 }"),
+            Ok(vec![
+                Stmt::Assign(
+                    0,
+                    vec![
+                        Term {
+                            sign: 1,
+                            factors: vec![Atom::Var(1), Atom::Const(2)]
+                        },
+                        Term {
+                            sign: -1,
+                            factors: vec![Atom::Const(3)]
+                        },
+                    ]
+                ),
+                Stmt::While(
+                    0,
+                    vec![
+                        Stmt::Out(vec![Term {
+                            sign: 1,
+                            factors: vec![Atom::Var(0)]
+                        }]),
+                        Stmt::Assign(
+                            0,
+                            vec![
+                                Term {
+                                    sign: 1,
+                                    factors: vec![Atom::Var(0)]
+                                },
+                                Term {
+                                    sign: -1,
+                                    factors: vec![Atom::Const(1)]
+                                },
+                            ]
+                        ),
+                    ]
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse_source("e = 1").is_err());
+        assert!(parse_source("a = ").is_err());
+        assert!(parse_source("while a != 1 { }").is_err());
+        assert!(parse_source("while a != 0 { a = 0").is_err());
+        assert!(parse_source("a = b / 2").is_err());
+    }
+
+    #[test]
+    fn test_compile() {
+        assert_eq!(
+            compile_source("b = 3\nwhile b != 0 {\na = a + b\nb = b - 1\n}").unwrap(),
+            "cpy 3 b
+jnz b 2
+jnz 1 9
+jnz b 2
+jnz 1 5
+cpy b c
+inc a
+dec c
+jnz c -2
+dec b
+jnz 1 -9"
+        );
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        assert_eq!(
+            round_trip("a = 3\nb = a * a + a - 2\nout b", [0; 4]).1,
+            vec![10]
+        );
+        assert_eq!(round_trip("a = -5 + b * 3 - b", [0, 7, 0, 0]).0[0], 9);
+        assert_eq!(
+            round_trip("a = 10 - 2 * b\nout a\na = b * b", [0, 6, 0, 0]),
+            ([36, 6, 0, 0], vec![-2])
+        );
+        assert_eq!(round_trip("a = a + a * 2 + 100", [4, 0, 0, 0]).0[0], 112);
+        assert_eq!(
+            round_trip("a = 0 * a - b + 7\nout -3\nout b + 1", [9, 0, 0, 0]),
+            ([7, 0, 0, 0], vec![-3, 1])
+        );
+        assert_eq!(round_trip("a = a - 4\nout a", [1, 0, 0, 0]).1, vec![-3]);
+    }
+
+    #[test]
+    fn test_negative_loop_operand() {
+        let stmts = parse_source("out a * 1 - 1").unwrap();
+        assert_eq!(
+            interpret(&stmts, &mut [-3, 0, 0, 0], &mut Vec::new()),
+            Err("Negative loop operand: a".to_string())
+        );
+    }
+
+    #[test]
+    fn test_loops() {
+        assert_eq!(
+            round_trip(
+                "b = 5\nwhile b != 0 {\na = a + b * b\nout a\nb = b - 1\n}",
+                [0; 4]
+            )
+            .1,
+            vec![25, 41, 50, 54, 55]
+        );
+
+        let source = "a = 3
+while a != 0 {
+    b = a
+    while b != 0 {
+        out b
+        b = b - 1
+    }
+    a = a - 1
+}";
+        assert_eq!(round_trip(source, [0; 4]).1, vec![3, 2, 1, 2, 1, 1]);
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        assert!(compile_source("a = b * c * d").is_err());
+        assert!(compile_source("a = b + c\nd = a * b").is_err());
+        assert!(compile_source("a = b * b * b").is_err());
+    }
+}
@@ -0,0 +1,392 @@
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Write};
+use std::str::FromStr;
+
+use super::{register_name, Machine, Operand};
+
+const HISTORY_LIMIT: usize = 10_000;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+}
+
+impl Comparison {
+    fn holds(&self, x: i32, y: i32) -> bool {
+        match self {
+            Comparison::Eq => x == y,
+            Comparison::Ne => x != y,
+            Comparison::Lt => x < y,
+            Comparison::Gt => x > y,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Breakpoint {
+    Pc(i32),
+    // Fires when the condition becomes true, not on every step while it stays true
+    Register(usize, Comparison, i32),
+}
+
+impl Breakpoint {
+    fn holds(&self, machine: &Machine) -> bool {
+        match *self {
+            Breakpoint::Pc(pc) => machine.pc == pc,
+            Breakpoint::Register(r, comparison, value) => {
+                comparison.holds(machine.registers[r], value)
+            }
+        }
+    }
+}
+
+impl FromStr for Breakpoint {
+    type Err = String;
+
+    // "12" or "a == 0"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split_whitespace().collect();
+
+        match parts[..] {
+            [pc] => pc
+                .parse()
+                .map(Breakpoint::Pc)
+                .map_err(|_| format!("Unknown pc: {}", pc)),
+            [register, comparison, value] => {
+                let Ok(Operand::Register(r)) = register.parse() else {
+                    return Err(format!("Unknown register: {}", register));
+                };
+                let comparison = match comparison {
+                    "==" => Comparison::Eq,
+                    "!=" => Comparison::Ne,
+                    "<" => Comparison::Lt,
+                    ">" => Comparison::Gt,
+                    _ => return Err(format!("Unknown comparison: {}", comparison)),
+                };
+                let value = value
+                    .parse()
+                    .map_err(|_| format!("Unknown value: {}", value))?;
+
+                Ok(Breakpoint::Register(r, comparison, value))
+            }
+            _ => Err(format!("Unknown breakpoint: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Stop {
+    Breakpoint(usize),
+    Halted,
+    // On an 'in' with nothing to read
+    Waiting,
+}
+
+pub struct Debugger {
+    pub machine: Machine,
+    pub breakpoints: Vec<Breakpoint>,
+    pub watches: Vec<usize>,
+    history: VecDeque<Machine>,
+}
+
+impl Debugger {
+    pub fn new(machine: Machine) -> Self {
+        Debugger {
+            machine,
+            breakpoints: Vec::new(),
+            watches: Vec::new(),
+            history: VecDeque::new(),
+        }
+    }
+
+    // Executes one instruction, keeping a snapshot of the state before it. Returns false, without
+    // a snapshot, if nothing could be executed.
+    pub fn step(&mut self) -> bool {
+        if self.machine.is_halted() || self.machine.is_waiting() {
+            return false;
+        }
+
+        let before = self.machine.clone();
+        if !self.machine.step() {
+            return false;
+        }
+
+        if self.history.len() == HISTORY_LIMIT {
+            self.history.pop_front();
+        }
+        self.history.push_back(before);
+        true
+    }
+
+    fn stopped(&self) -> Stop {
+        if self.machine.is_waiting() {
+            Stop::Waiting
+        } else {
+            Stop::Halted
+        }
+    }
+
+    // Runs until a breakpoint fires or the program halts. Always executes at least one
+    // instruction, so continuing from a breakpoint does not stop on it again right away.
+    pub fn cont(&mut self) -> Stop {
+        loop {
+            let before: Vec<bool> = self
+                .breakpoints
+                .iter()
+                .map(|b| b.holds(&self.machine))
+                .collect();
+
+            if !self.step() {
+                return self.stopped();
+            }
+
+            let hit = self.breakpoints.iter().enumerate().position(|(i, b)| {
+                b.holds(&self.machine) && !(before[i] && matches!(b, Breakpoint::Register(..)))
+            });
+            if let Some(i) = hit {
+                return Stop::Breakpoint(i);
+            }
+        }
+    }
+
+    // Restores the state from 'steps' steps ago, or as far back as the history goes. Returns the
+    // number of steps actually rewound.
+    pub fn rewind(&mut self, steps: usize) -> usize {
+        let steps = steps.min(self.history.len());
+        if steps > 0 {
+            self.history.truncate(self.history.len() - steps + 1);
+            self.machine = self.history.pop_back().unwrap();
+        }
+
+        steps
+    }
+
+    // The current program, including any toggled instructions, with the pc marked.
+    pub fn listing(&self) -> String {
+        self.machine
+            .program
+            .iter()
+            .enumerate()
+            .map(|(pc, instruction)| {
+                let marker = if pc as i32 == self.machine.pc {
+                    "=>"
+                } else {
+                    "  "
+                };
+                format!("{} {:>3}  {}\n", marker, pc, instruction)
+            })
+            .collect()
+    }
+
+    pub fn registers(&self) -> String {
+        format_registers(&self.machine, 0..4)
+    }
+
+    pub fn watched(&self) -> String {
+        format_registers(&self.machine, self.watches.iter().copied())
+    }
+
+    fn status(&self) -> String {
+        let mut status = format!("pc={}", self.machine.pc);
+        if !self.watches.is_empty() {
+            status += &format!(" {}", self.watched());
+        }
+        status
+    }
+
+    fn describe(&self, stop: Stop) -> String {
+        match stop {
+            Stop::Breakpoint(i) => format!("breakpoint {}, {}", i, self.status()),
+            Stop::Halted => format!("halted, {}", self.status()),
+            Stop::Waiting => format!("waiting for input, {}", self.status()),
+        }
+    }
+
+    // Executes one debugger command and returns what to show to the user.
+    pub fn command(&mut self, line: &str) -> String {
+        let (cmd, args) = line.trim().split_once(' ').unwrap_or((line.trim(), ""));
+
+        match cmd {
+            "s" | "step" => {
+                let n = args.parse().unwrap_or(1);
+                let taken = (0..n).take_while(|_| self.step()).count();
+                if taken < n {
+                    self.describe(self.stopped())
+                } else {
+                    self.status()
+                }
+            }
+            "c" | "continue" => {
+                let stop = self.cont();
+                self.describe(stop)
+            }
+            "i" | "input" => match args.parse() {
+                Ok(value) => {
+                    self.machine.input.push_back(value);
+                    format!("input {:?}", self.machine.input)
+                }
+                Err(_) => format!("Unknown value: {}", args),
+            },
+            "b" | "break" => match args.parse() {
+                Ok(breakpoint) => {
+                    self.breakpoints.push(breakpoint);
+                    format!(
+                        "breakpoint {}: {:?}",
+                        self.breakpoints.len() - 1,
+                        breakpoint
+                    )
+                }
+                Err(e) => e,
+            },
+            "w" | "watch" => match args.parse() {
+                Ok(Operand::Register(r)) => {
+                    self.watches.push(r);
+                    self.watched()
+                }
+                _ => format!("Unknown register: {}", args),
+            },
+            "back" => {
+                let rewound = self.rewind(args.parse().unwrap_or(1));
+                format!("rewound {} steps, {}", rewound, self.status())
+            }
+            "l" | "list" => self.listing(),
+            "r" | "regs" => self.registers(),
+            "o" | "output" => format!("{:?}", self.machine.output),
+            _ => format!("Unknown command: {}", line),
+        }
+    }
+
+    // Reads commands from 'input' until it ends or 'q' is given.
+    pub fn repl(&mut self, input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+        write!(output, "(dbg) ")?;
+        output.flush()?;
+
+        for line in input.lines() {
+            let line = line?;
+            if matches!(line.trim(), "q" | "quit") {
+                break;
+            }
+            if !line.trim().is_empty() {
+                writeln!(output, "{}", self.command(&line).trim_end())?;
+            }
+            write!(output, "(dbg) ")?;
+            output.flush()?;
+        }
+
+        Ok(())
+    }
+}
+
+fn format_registers(machine: &Machine, registers: impl Iterator<Item = usize>) -> String {
+    registers
+        .map(|r| format!("{}={}", register_name(r), machine.registers[r]))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembunny::parse;
+
+    static TEST_INPUT: &str = "cpy 3 b
+inc a
+dec b
+jnz b -2
+tgl 1
+inc c";
+
+    fn debugger() -> Debugger {
+        Debugger::new(Machine::new(parse(TEST_INPUT), [0; 4]))
+    }
+
+    #[test]
+    fn test_pc_breakpoint() {
+        let mut debugger = debugger();
+        debugger.breakpoints.push(Breakpoint::Pc(3));
+
+        assert_eq!(debugger.cont(), Stop::Breakpoint(0));
+        assert_eq!(debugger.machine.registers, [1, 2, 0, 0]);
+        assert_eq!(debugger.cont(), Stop::Breakpoint(0));
+        assert_eq!(debugger.machine.registers, [2, 1, 0, 0]);
+    }
+
+    #[test]
+    fn test_register_breakpoint() {
+        let mut debugger = debugger();
+        debugger.breakpoints.push("b == 0".parse().unwrap());
+
+        assert_eq!(debugger.cont(), Stop::Breakpoint(0));
+        assert_eq!(debugger.machine.pc, 3);
+        assert_eq!(debugger.machine.registers, [3, 0, 0, 0]);
+
+        // Stays true for the rest of the run, so it does not fire again
+        assert_eq!(debugger.cont(), Stop::Halted);
+        assert_eq!(debugger.machine.registers, [3, 0, -1, 0]);
+    }
+
+    #[test]
+    fn test_step_and_rewind() {
+        let mut debugger = debugger();
+        while debugger.step() {}
+        assert_eq!(debugger.machine.program[5].to_string(), "dec c");
+
+        assert_eq!(debugger.rewind(2), 2);
+        assert_eq!(debugger.machine.pc, 4);
+        assert_eq!(debugger.machine.program[5].to_string(), "inc c");
+
+        assert_eq!(debugger.rewind(100), 10);
+        assert_eq!(debugger.machine.pc, 0);
+        assert_eq!(debugger.machine.registers, [0; 4]);
+        assert_eq!(debugger.rewind(1), 0);
+    }
+
+    #[test]
+    fn test_commands() {
+        let mut debugger = debugger();
+
+        assert_eq!(debugger.command("watch a"), "a=0");
+        assert_eq!(
+            debugger.command("break 4"),
+            "breakpoint 0: Pc(4)".to_string()
+        );
+        assert_eq!(debugger.command("c"), "breakpoint 0, pc=4 a=3");
+        assert_eq!(debugger.command("step 5"), "halted, pc=6 a=3");
+        assert_eq!(debugger.command("regs"), "a=3 b=0 c=-1 d=0");
+        assert_eq!(debugger.command("back"), "rewound 1 steps, pc=5 a=3");
+        assert!(debugger.command("list").contains("=>   5  dec c"));
+        assert_eq!(debugger.command("break e > 1"), "Unknown register: e");
+    }
+
+    #[test]
+    fn test_waiting_for_input() {
+        let mut debugger = Debugger::new(Machine::new(parse("in a\ninc a"), [0; 4]));
+
+        assert_eq!(debugger.cont(), Stop::Waiting);
+        assert!(!debugger.step());
+        assert_eq!(debugger.rewind(1), 0);
+
+        assert_eq!(debugger.command("step"), "waiting for input, pc=0");
+        assert_eq!(debugger.command("input 4"), "input [4]");
+        assert_eq!(debugger.command("c"), "halted, pc=2");
+        assert_eq!(debugger.machine.registers[0], 5);
+        assert_eq!(debugger.rewind(5), 2);
+    }
+
+    #[test]
+    fn test_repl() {
+        let mut debugger = debugger();
+        let mut output = Vec::new();
+        debugger
+            .repl("step 2\n\nregs\nq\nstep\n".as_bytes(), &mut output)
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "(dbg) pc=2\n(dbg) (dbg) a=1 b=3 c=0 d=0\n(dbg) "
+        );
+    }
+}
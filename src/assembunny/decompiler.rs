@@ -0,0 +1,549 @@
+use std::collections::BTreeSet;
+
+use super::optimizer::{self, Block};
+use super::{register_name, Instruction, Operand};
+
+enum Stmt {
+    Line(String),
+    Copy(usize, Operand),
+    // target += sign * product, leaving the loop counters at zero
+    Accumulate {
+        target: usize,
+        sign: i32,
+        product: Vec<Operand>,
+        cleared: Vec<usize>,
+    },
+    If(String, Vec<Stmt>),
+    // Condition checked at the end of each round, None if the loop never ends by itself
+    Loop(Option<String>, Vec<Stmt>),
+    Label(usize),
+}
+
+// cpy K c, jnz n 2, jnz 1 6, dec n, dec c, jnz c -4, inc q, jnz 1 -7
+//
+// Leaves q += n / K and c = K - n % K.
+struct DivMod {
+    divisor: i32,
+    dividend: usize,
+    quotient: usize,
+    remainder: usize,
+}
+
+const DIV_MOD_LEN: usize = 8;
+
+fn find_div_mod(program: &[Instruction]) -> Option<DivMod> {
+    use Instruction::*;
+    use Operand::{Register as R, Value as V};
+
+    let [Cpy(V(divisor), R(c)), Jnz(R(n), V(2)), Jnz(V(always), V(6)), Dec(R(n_dec)), Dec(R(c_dec)), Jnz(R(c_jnz), V(-4)), Inc(R(q)), Jnz(V(always_back), V(-7)), ..] =
+        *program
+    else {
+        return None;
+    };
+
+    let shape = n == n_dec && c == c_dec && c == c_jnz && always != 0 && always_back != 0;
+    let distinct = n != c && q != n && q != c;
+
+    (shape && distinct && divisor > 0).then_some(DivMod {
+        divisor,
+        dividend: n,
+        quotient: q,
+        remainder: c,
+    })
+}
+
+struct Decompiler<'a> {
+    program: &'a [Instruction],
+    blocks: Vec<Option<Block>>,
+    // Jumps with a constant target, as (from, to)
+    jumps: Vec<(usize, usize)>,
+    gotos: BTreeSet<usize>,
+}
+
+impl Decompiler<'_> {
+    // A pattern is only used if nothing outside it jumps into its middle.
+    fn is_closed(&self, start: usize, len: usize) -> bool {
+        let inside = start..start + len;
+        !self
+            .jumps
+            .iter()
+            .any(|(from, to)| !inside.contains(from) && inside.contains(to) && *to != start)
+    }
+
+    // The last instruction of the outermost loop starting at 'pc' and ending before 'end'.
+    fn loop_tail(&self, pc: usize, end: usize) -> Option<usize> {
+        self.jumps
+            .iter()
+            .filter(|&&(from, to)| to == pc && (pc..end).contains(&from))
+            .map(|&(from, _)| from)
+            .max()
+    }
+
+    fn jump(&mut self, target: usize, exit: Option<usize>) -> String {
+        if Some(target) == exit {
+            "break".to_string()
+        } else if target >= self.program.len() {
+            "halt".to_string()
+        } else {
+            self.gotos.insert(target);
+            format!("goto L{}", target)
+        }
+    }
+
+    // Turns a known loop shape at 'pc' into statements and returns its length.
+    fn pattern(&self, pc: usize, end: usize, stmts: &mut Vec<Stmt>) -> Option<usize> {
+        if let Some(div_mod) = find_div_mod(&self.program[pc..end]) {
+            if self.is_closed(pc, DIV_MOD_LEN) {
+                let DivMod {
+                    divisor,
+                    dividend,
+                    quotient,
+                    remainder,
+                } = div_mod;
+                let (n, q, c) = (
+                    register_name(dividend),
+                    register_name(quotient),
+                    register_name(remainder),
+                );
+                stmts.push(Stmt::Line(format!("{} += {} / {}", q, n, divisor)));
+                stmts.push(Stmt::Line(format!(
+                    "{} = {} - {} % {}",
+                    c, divisor, n, divisor
+                )));
+                stmts.push(Stmt::Line(format!("{} = 0", n)));
+                return Some(DIV_MOD_LEN);
+            }
+        }
+
+        let block = self.blocks[pc]?;
+        if pc + block.len() > end || !self.is_closed(pc, block.len()) {
+            return None;
+        }
+
+        stmts.push(match block {
+            Block::Add {
+                target,
+                target_step,
+                counter,
+                counter_step,
+            } => Stmt::Accumulate {
+                target,
+                sign: -target_step * counter_step,
+                product: vec![Operand::Register(counter)],
+                cleared: vec![counter],
+            },
+            Block::Mul {
+                source,
+                target,
+                target_step,
+                inner,
+                inner_step,
+                outer,
+                outer_step,
+            } => Stmt::Accumulate {
+                target,
+                sign: target_step * inner_step * outer_step,
+                product: vec![source, Operand::Register(outer)],
+                cleared: vec![inner, outer],
+            },
+        });
+
+        Some(block.len())
+    }
+
+    // Structures the instructions in start..end. 'exit' is where a jump leaves the innermost
+    // enclosing loop.
+    fn structure(&mut self, start: usize, end: usize, exit: Option<usize>) -> Vec<Stmt> {
+        let mut stmts = Vec::new();
+        let mut pc = start;
+
+        while pc < end {
+            if self.jumps.iter().any(|&(_, to)| to == pc) {
+                stmts.push(Stmt::Label(pc));
+            }
+
+            let tail = self.loop_tail(pc, end);
+            let mut pattern = Vec::new();
+            if let Some(len) = self.pattern(pc, end, &mut pattern) {
+                if tail.is_none_or(|tail| tail < pc + len) {
+                    stmts.extend(pattern);
+                    pc += len;
+                    continue;
+                }
+            }
+
+            if let Some(tail) = tail {
+                let mut body = self.structure(pc, tail, Some(tail + 1));
+                // The label for the loop head is already outside the loop
+                if let Some(Stmt::Label(_)) = body.first() {
+                    body.remove(0);
+                }
+                let condition = match self.program[tail] {
+                    Instruction::Jnz(Operand::Value(_), _) => None,
+                    Instruction::Jnz(x, _) => Some(format!("{} != 0", x)),
+                    _ => unreachable!(),
+                };
+                stmts.push(Stmt::Loop(condition, body));
+                pc = tail + 1;
+                continue;
+            }
+
+            let stmt = match self.program[pc] {
+                Instruction::Jnz(Operand::Value(0), _) => {
+                    pc += 1;
+                    continue;
+                }
+                Instruction::Jnz(Operand::Value(_), Operand::Value(offset)) => {
+                    Stmt::Line(self.jump(target(pc, offset), exit))
+                }
+                Instruction::Jnz(x, Operand::Value(offset))
+                    if offset > 1 && pc + offset as usize <= end =>
+                {
+                    let body = self.structure(pc + 1, pc + offset as usize, exit);
+                    stmts.push(Stmt::If(format!("{} == 0", x), body));
+                    pc += offset as usize;
+                    continue;
+                }
+                Instruction::Jnz(x, Operand::Value(offset)) => {
+                    let jump = self.jump(target(pc, offset), exit);
+                    Stmt::If(format!("{} != 0", x), vec![Stmt::Line(jump)])
+                }
+                Instruction::Jnz(Operand::Value(_), y) => {
+                    Stmt::Line(format!("goto {} + {}", pc, y))
+                }
+                Instruction::Jnz(x, y) => {
+                    Stmt::Line(format!("if {} != 0 {{ goto {} + {} }}", x, pc, y))
+                }
+                Instruction::Cpy(x, Operand::Register(y)) => Stmt::Copy(y, x),
+                Instruction::Inc(Operand::Register(x)) => {
+                    Stmt::Line(format!("{} += 1", register_name(x)))
+                }
+                Instruction::Dec(Operand::Register(x)) => {
+                    Stmt::Line(format!("{} -= 1", register_name(x)))
+                }
+                Instruction::Tgl(x) => Stmt::Line(format!("toggle({} + {})", pc, x)),
+                Instruction::Out(x) => Stmt::Line(format!("out({})", x)),
+                Instruction::In(Operand::Register(x)) => {
+                    Stmt::Line(format!("{} = input()", register_name(x)))
+                }
+                Instruction::Custom(custom) => {
+                    let operands: Vec<String> =
+                        custom.operands().iter().map(|o| o.to_string()).collect();
+                    Stmt::Line(format!("{}({})", custom.opcode.name(), operands.join(", ")))
+                }
+                instruction => Stmt::Line(format!("skip  // {}", instruction)),
+            };
+            stmts.push(stmt);
+            pc += 1;
+        }
+
+        stmts
+    }
+}
+
+// Jumps to before the start of the program end up past its end, as both halt. In i64, as the
+// target need not fit an i32.
+fn target(pc: usize, offset: i32) -> usize {
+    usize::try_from(pc as i64 + offset as i64).unwrap_or(usize::MAX)
+}
+
+fn product(product: &[Operand]) -> String {
+    product
+        .iter()
+        .map(|o| o.to_string())
+        .collect::<Vec<_>>()
+        .join(" * ")
+}
+
+// Drops the labels nothing jumps to, and folds a preceding reset of the target into an
+// accumulation, turning 'x = 0, x += y' into 'x = y', and 't = x, x = 0, x += s * t' into
+// 'x *= s'.
+fn merge(stmts: Vec<Stmt>, gotos: &BTreeSet<usize>) -> Vec<Stmt> {
+    let mut merged: Vec<Stmt> = Vec::new();
+
+    for stmt in stmts {
+        let stmt = match stmt {
+            Stmt::Label(pc) if !gotos.contains(&pc) => continue,
+            Stmt::If(condition, body) => Stmt::If(condition, merge(body, gotos)),
+            Stmt::Loop(condition, body) => Stmt::Loop(condition, merge(body, gotos)),
+            stmt => stmt,
+        };
+        let Stmt::Accumulate {
+            target,
+            sign,
+            product: factors,
+            cleared,
+        } = stmt
+        else {
+            merged.push(stmt);
+            continue;
+        };
+        let t = register_name(target);
+        let op = if sign > 0 { "+=" } else { "-=" };
+
+        let reset = matches!(merged.last(), Some(Stmt::Copy(r, Operand::Value(0))) if *r == target);
+        let line = if reset {
+            merged.pop();
+            let saved = match (merged.last(), &factors[..]) {
+                (Some(Stmt::Copy(saved, Operand::Register(r))), [source, Operand::Register(f)])
+                    if *r == target && f == saved && *source != Operand::Register(target) =>
+                {
+                    Some(*source)
+                }
+                _ => None,
+            };
+            match saved {
+                Some(source) if sign > 0 => {
+                    merged.pop();
+                    format!("{} *= {}", t, source)
+                }
+                _ if sign > 0 => format!("{} = {}", t, product(&factors)),
+                _ => format!("{} = -{}", t, product(&factors)),
+            }
+        } else {
+            format!("{} {} {}", t, op, product(&factors))
+        };
+
+        merged.push(Stmt::Line(line));
+        for r in cleared {
+            merged.push(Stmt::Line(format!("{} = 0", register_name(r))));
+        }
+    }
+
+    merged
+}
+
+fn render(stmts: &[Stmt], depth: usize, out: &mut String) {
+    let indent = "    ".repeat(depth);
+
+    for stmt in stmts {
+        match stmt {
+            Stmt::Line(line) => *out += &format!("{}{}\n", indent, line),
+            Stmt::Copy(y, x) => *out += &format!("{}{} = {}\n", indent, register_name(*y), x),
+            Stmt::Accumulate { .. } => unreachable!("merged away"),
+            Stmt::If(condition, body) => match &body[..] {
+                [Stmt::Line(line)] => {
+                    *out += &format!("{}if {} {{ {} }}\n", indent, condition, line);
+                }
+                _ => {
+                    *out += &format!("{}if {} {{\n", indent, condition);
+                    render(body, depth + 1, out);
+                    *out += &format!("{}}}\n", indent);
+                }
+            },
+            Stmt::Loop(condition, body) => {
+                let open = if condition.is_some() { "do" } else { "loop" };
+                *out += &format!("{}{} {{\n", indent, open);
+                render(body, depth + 1, out);
+                match condition {
+                    Some(condition) => *out += &format!("{}}} while {}\n", indent, condition),
+                    None => *out += &format!("{}}}\n", indent),
+                }
+            }
+            Stmt::Label(pc) => *out += &format!("L{}:\n", pc),
+        }
+    }
+}
+
+// Turns the program into pseudocode with structured loops and the arithmetic that the
+// inc/dec/jnz loops compute.
+pub fn decompile(program: &[Instruction]) -> String {
+    let mut decompiler = Decompiler {
+        program,
+        blocks: optimizer::find_blocks(program),
+        jumps: program
+            .iter()
+            .enumerate()
+            .filter_map(|(pc, instruction)| match instruction {
+                Instruction::Jnz(x, Operand::Value(offset)) if *x != Operand::Value(0) => {
+                    Some((pc, target(pc, *offset)))
+                }
+                _ => None,
+            })
+            .collect(),
+        gotos: BTreeSet::new(),
+    };
+
+    let stmts = decompiler.structure(0, program.len(), None);
+    let stmts = merge(stmts, &decompiler.gotos);
+
+    let mut out = String::new();
+    render(&stmts, 0, &mut out);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembunny::parse;
+
+    #[test]
+    fn test_add_and_multiply() {
+        let program = parse(
+            "cpy a d
+cpy 0 a
+cpy b c
+inc a
+dec c
+jnz c -2
+dec d
+jnz d -5
+cpy 0 b
+inc b
+dec c
+jnz c -2
+inc d
+inc a
+jnz d -2",
+        );
+
+        assert_eq!(
+            decompile(&program),
+            "a *= b
+c = 0
+d = 0
+b = c
+c = 0
+a -= d
+d = 0
+"
+        );
+    }
+
+    #[test]
+    fn test_branches_and_gotos() {
+        let program = parse(
+            "jnz a 3
+inc b
+inc b
+jnz c 2
+jnz 1 4
+dec c
+jnz 0 0
+jnz 1 -4
+jnz d -8
+out b",
+        );
+
+        assert_eq!(
+            decompile(&program),
+            "do {
+    if a == 0 {
+        b += 1
+        b += 1
+    }
+    loop {
+        if c == 0 { break }
+        c -= 1
+    }
+} while d != 0
+out(b)
+"
+        );
+
+        let program = parse("inc a\njnz b 4\ninc a\njnz 1 -3\ninc c\ninc d");
+
+        assert_eq!(
+            decompile(&program),
+            "loop {
+    a += 1
+    if b != 0 { goto L5 }
+    a += 1
+}
+c += 1
+L5:
+d += 1
+"
+        );
+    }
+
+    #[test]
+    fn test_jump_past_i32() {
+        assert_eq!(
+            decompile(&parse("inc a\njnz a 2147483647")),
+            "a += 1\nif a != 0 { halt }\n"
+        );
+    }
+
+    #[test]
+    fn test_jump_into_loop_head_and_register_jumps() {
+        let program = parse(
+            "jnz a 2
+jnz 1 2
+cpy 3 b
+inc c
+out c
+dec b
+jnz b -3
+tgl c
+jnz 1 c
+jnz d c",
+        );
+
+        assert_eq!(
+            decompile(&program),
+            "if a == 0 { goto L3 }
+b = 3
+L3:
+do {
+    c += 1
+    out(c)
+    b -= 1
+} while b != 0
+toggle(7 + c)
+goto 8 + c
+if d != 0 { goto 9 + c }
+"
+        );
+    }
+
+    #[test]
+    fn test_day_25_print_loop() {
+        let program = parse(
+            "cpy d a
+jnz 0 0
+cpy a b
+cpy 0 a
+cpy 2 c
+jnz b 2
+jnz 1 6
+dec b
+dec c
+jnz c -4
+inc a
+jnz 1 -7
+cpy 2 b
+jnz c 2
+jnz 1 4
+dec b
+dec c
+jnz 1 -4
+jnz 0 0
+out b
+jnz a -19
+jnz 1 -21",
+        );
+
+        assert_eq!(
+            decompile(&program),
+            "loop {
+    a = d
+    do {
+        b = a
+        a = 0
+        a += b / 2
+        c = 2 - b % 2
+        b = 0
+        b = 2
+        loop {
+            if c == 0 { break }
+            b -= 1
+            c -= 1
+        }
+        out(b)
+    } while a != 0
+}
+"
+        );
+    }
+}
@@ -0,0 +1,530 @@
+use std::fmt;
+
+use super::symbolic::{self, Expr};
+use super::threaded::Threaded;
+use super::word::Word;
+use super::{Instruction, Machine, Operand};
+
+// Programs still running after this many steps are treated as not halting
+const MAX_STEPS: usize = 100_000;
+
+// xorshift64*, so that a failing run can be reproduced from its seed
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    // Inclusive on both ends
+    fn range(&mut self, low: i32, high: i32) -> i32 {
+        low + self.below((high - low + 1) as usize) as i32
+    }
+
+    fn register(&mut self) -> usize {
+        self.below(4)
+    }
+
+    fn register_except(&mut self, taken: &[usize]) -> usize {
+        loop {
+            let r = self.register();
+            if !taken.contains(&r) {
+                return r;
+            }
+        }
+    }
+}
+
+fn reg(r: usize) -> Operand {
+    Operand::Register(r)
+}
+
+fn val(v: i32) -> Operand {
+    Operand::Value(v)
+}
+
+// A straight-line instruction that leaves the registers in 'keep' alone
+fn simple(rng: &mut Rng, keep: &[usize]) -> Instruction {
+    let target = reg(rng.register_except(keep));
+    let source = match rng.below(2) {
+        0 => val(rng.range(-5, 5)),
+        _ => reg(rng.register()),
+    };
+
+    match rng.below(4) {
+        0 => Instruction::Cpy(source, target),
+        1 => Instruction::Inc(target),
+        2 => Instruction::Dec(target),
+        _ => Instruction::Out(source),
+    }
+}
+
+// 'inc' or 'dec' in the direction that brings a counter starting at 'start' down to zero
+fn count_down(start: i32, counter: usize) -> Instruction {
+    if start > 0 {
+        Instruction::Dec(reg(counter))
+    } else {
+        Instruction::Inc(reg(counter))
+    }
+}
+
+// The edges of i32, for offsets and values the puzzles never use
+const EXTREMES: [i32; 4] = [i32::MAX, i32::MAX - 1, i32::MIN, i32::MIN + 1];
+
+// An add or multiply loop that is run a few times over. A 'tgl' after it flips one of its
+// instructions on every pass, so that the loop keeps moving in and out of a fusable shape: its
+// step between 'inc' and 'dec', or a 'jnz' closing it into a 'cpy' that is skipped and back.
+// Counters are never toggled, so every pass still ends.
+fn toggled_loop(rng: &mut Rng) -> Vec<Instruction> {
+    let repeat = rng.register();
+    let counter = rng.register_except(&[repeat]);
+    let target = rng.register_except(&[repeat, counter]);
+    let step = if rng.below(2) == 0 {
+        Instruction::Inc(reg(target))
+    } else {
+        Instruction::Dec(reg(target))
+    };
+
+    // The loop, and the indices of the instructions that may be toggled
+    let (mut body, toggleable) = if rng.below(2) == 0 {
+        let start = rng.range(1, 4) * if rng.below(2) == 0 { 1 } else { -1 };
+        let body = vec![
+            Instruction::Cpy(val(start), reg(counter)),
+            step,
+            count_down(start, counter),
+            Instruction::Jnz(reg(counter), val(-2)),
+        ];
+        (body, vec![1, 3])
+    } else {
+        let inner = rng.register_except(&[repeat, counter, target]);
+        let body = vec![
+            Instruction::Cpy(val(rng.range(1, 4)), reg(counter)),
+            Instruction::Cpy(val(rng.range(1, 4)), reg(inner)),
+            step,
+            Instruction::Dec(reg(inner)),
+            Instruction::Jnz(reg(inner), val(-2)),
+            Instruction::Dec(reg(counter)),
+            Instruction::Jnz(reg(counter), val(-5)),
+        ];
+        (body, vec![2, 4, 6])
+    };
+
+    // Starting with the 'jnz' as a 'cpy' makes the first pass the one out of shape
+    let toggled = toggleable[rng.below(toggleable.len())];
+    if rng.below(2) == 0 {
+        body[toggled] = body[toggled].toggled();
+    }
+
+    let len = body.len() as i32;
+    let mut snippet = vec![Instruction::Cpy(val(rng.range(2, 3)), reg(repeat))];
+    snippet.extend(body);
+    snippet.extend([
+        Instruction::Tgl(val(toggled as i32 - len)),
+        Instruction::Dec(reg(repeat)),
+        Instruction::Jnz(reg(repeat), val(-(len + 2))),
+    ]);
+    snippet
+}
+
+// A jump, 'tgl', 'cpy' or 'out' with an operand at the edge of i32. Jumps that far and 'tgl'
+// targets that far are always outside the program. Values that far may overflow what follows,
+// which only keeps the program out of the comparison.
+fn extreme(rng: &mut Rng) -> Instruction {
+    let value = val(EXTREMES[rng.below(EXTREMES.len())]);
+
+    match rng.below(4) {
+        0 => Instruction::Jnz(reg(rng.register()), value),
+        1 => Instruction::Tgl(value),
+        2 => Instruction::Cpy(value, reg(rng.register())),
+        _ => Instruction::Out(value),
+    }
+}
+
+// Generates a random program that always halts. Loops only ever run over a counter that is set
+// right before them and that nothing else in the loop touches. Forward jumps only skip
+// straight-line code. A 'tgl' only targets top level 'inc', 'dec' and 'out' instructions, which
+// can only toggle into each other, or points outside the program, apart from the ones in toggled
+// loops that only ever change their own loop.
+pub fn generate(rng: &mut Rng, snippets: usize) -> Vec<Instruction> {
+    let mut program = Vec::new();
+    let mut toggle_targets = Vec::new();
+    let mut toggles = Vec::new();
+
+    for _ in 0..snippets {
+        match rng.below(9) {
+            0 | 1 => {
+                let instruction = simple(rng, &[]);
+                if !matches!(instruction, Instruction::Cpy(..)) {
+                    toggle_targets.push(program.len());
+                }
+                program.push(instruction);
+            }
+            2 => {
+                // Counted loop around a random body
+                let counter = rng.register();
+                let start = if rng.below(2) == 0 {
+                    rng.range(1, 4)
+                } else {
+                    rng.range(-4, -1)
+                };
+                let body: Vec<_> = (0..rng.range(1, 3))
+                    .map(|_| simple(rng, &[counter]))
+                    .collect();
+
+                program.push(Instruction::Cpy(val(start), reg(counter)));
+                program.extend(&body);
+                program.push(count_down(start, counter));
+                program.push(Instruction::Jnz(
+                    reg(counter),
+                    val(-(body.len() as i32 + 1)),
+                ));
+            }
+            3 => {
+                // Add loop, in either order
+                let counter = rng.register();
+                let target = rng.register_except(&[counter]);
+                let start = rng.range(1, 6) * if rng.below(2) == 0 { 1 } else { -1 };
+                let step = if rng.below(2) == 0 {
+                    Instruction::Inc(reg(target))
+                } else {
+                    Instruction::Dec(reg(target))
+                };
+
+                program.push(Instruction::Cpy(val(start), reg(counter)));
+                if rng.below(2) == 0 {
+                    program.extend([step, count_down(start, counter)]);
+                } else {
+                    program.extend([count_down(start, counter), step]);
+                }
+                program.push(Instruction::Jnz(reg(counter), val(-2)));
+            }
+            4 => {
+                // Multiply loop, from a register or a constant
+                let outer = rng.register();
+                let inner = rng.register_except(&[outer]);
+                let target = rng.register_except(&[outer, inner]);
+                let source = if rng.below(2) == 0 {
+                    let source = rng.register_except(&[outer, inner, target]);
+                    program.push(Instruction::Cpy(val(rng.range(1, 5)), reg(source)));
+                    reg(source)
+                } else {
+                    val(rng.range(1, 5))
+                };
+
+                program.push(Instruction::Cpy(val(rng.range(1, 5)), reg(outer)));
+                program.extend([
+                    Instruction::Cpy(source, reg(inner)),
+                    Instruction::Inc(reg(target)),
+                    Instruction::Dec(reg(inner)),
+                    Instruction::Jnz(reg(inner), val(-2)),
+                    Instruction::Dec(reg(outer)),
+                    Instruction::Jnz(reg(outer), val(-5)),
+                ]);
+            }
+            5 => {
+                // Forward jump over straight-line code
+                let skipped = rng.range(1, 3);
+                let condition = match rng.below(2) {
+                    0 => val(rng.range(0, 1)),
+                    _ => reg(rng.register()),
+                };
+                program.push(Instruction::Jnz(condition, val(skipped + 1)));
+                for _ in 0..skipped {
+                    program.push(simple(rng, &[]));
+                }
+            }
+            6 => program.extend(toggled_loop(rng)),
+            7 => program.push(extreme(rng)),
+            _ => {
+                toggles.push(program.len());
+                program.push(Instruction::Tgl(val(0)));
+            }
+        }
+    }
+
+    // Targets are picked once the whole program is known
+    for pc in toggles {
+        let target = if toggle_targets.is_empty() || rng.below(4) == 0 {
+            program.len() as i32 + rng.range(0, 3)
+        } else {
+            toggle_targets[rng.below(toggle_targets.len())] as i32
+        };
+        program[pc] = Instruction::Tgl(val(target - pc as i32));
+    }
+
+    program
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Strategy {
+    Interpreted,
+    Optimized,
+    Wide,
+    Symbolic,
+    // Wraps around on overflow, which only matters for programs that are not compared anyway
+    Threaded,
+}
+
+const STRATEGIES: [Strategy; 5] = [
+    Strategy::Interpreted,
+    Strategy::Optimized,
+    Strategy::Wide,
+    Strategy::Symbolic,
+    Strategy::Threaded,
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outcome {
+    pub registers: [i64; 4],
+    pub output: Vec<i64>,
+}
+
+fn run_machine<W: Word + Copy + Into<i64>>(mut machine: Machine<W>) -> Option<Outcome> {
+    for _ in 0..MAX_STEPS {
+        if !machine.step() {
+            return machine.overflow.is_none().then(|| Outcome {
+                registers: machine.registers.map(Into::into),
+                output: machine.output.iter().map(|&v| v.into()).collect(),
+            });
+        }
+    }
+
+    None
+}
+
+// The final registers and output, or None if the program did not halt properly
+pub fn run(strategy: Strategy, program: &[Instruction], registers: [i32; 4]) -> Option<Outcome> {
+    match strategy {
+        Strategy::Interpreted => run_machine(Machine::new(program.to_vec(), registers)),
+        Strategy::Optimized => {
+            let mut machine = Machine::new(program.to_vec(), registers);
+            machine.optimize();
+            run_machine(machine)
+        }
+        Strategy::Wide => {
+            let mut machine = Machine::new(program.to_vec(), registers.map(i64::from));
+            machine.optimize();
+            run_machine(machine)
+        }
+        Strategy::Symbolic => {
+            let state = symbolic::execute(
+                program,
+                0,
+                registers.map(|r| Expr::constant(r as i64)),
+                program.len(),
+            )
+            .ok()?;
+            let constant = |expr: &Expr| expr.as_constant();
+
+            Some(Outcome {
+                registers: [
+                    constant(&state.registers[0])?,
+                    constant(&state.registers[1])?,
+                    constant(&state.registers[2])?,
+                    constant(&state.registers[3])?,
+                ],
+                output: state.output.iter().map(constant).collect::<Option<_>>()?,
+            })
+        }
+        Strategy::Threaded => {
+            let mut threaded = Threaded::new(program.to_vec(), registers);
+            threaded.run_for(MAX_STEPS);
+            threaded.is_halted().then(|| Outcome {
+                registers: threaded.state.registers.map(i64::from),
+                output: threaded.state.output.iter().map(|&v| v.into()).collect(),
+            })
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    pub program: Vec<Instruction>,
+    pub registers: [i32; 4],
+    pub outcomes: Vec<(Strategy, Option<Outcome>)>,
+}
+
+// The program as text, followed by what each strategy made of it
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "registers {:?}", self.registers)?;
+        for instruction in &self.program {
+            writeln!(f, "{}", instruction)?;
+        }
+        for (strategy, outcome) in &self.outcomes {
+            writeln!(f, "{:?}: {:?}", strategy, outcome)?;
+        }
+        Ok(())
+    }
+}
+
+// Runs the program with every strategy. Only programs the plain interpreter halts on count.
+pub fn compare(program: &[Instruction], registers: [i32; 4]) -> Option<Mismatch> {
+    let outcomes: Vec<_> = STRATEGIES
+        .iter()
+        .map(|&strategy| (strategy, run(strategy, program, registers)))
+        .collect();
+
+    let expected = &outcomes[0].1;
+    let differs = expected.is_some() && outcomes.iter().any(|(_, outcome)| outcome != expected);
+
+    differs.then(|| Mismatch {
+        program: program.to_vec(),
+        registers,
+        outcomes,
+    })
+}
+
+fn simplified(instruction: Instruction) -> Vec<Instruction> {
+    let smaller = |operand| match operand {
+        Operand::Value(v) if v != 0 => vec![val(0), val(v / 2)],
+        _ => vec![],
+    };
+
+    match instruction {
+        Instruction::Cpy(x, y) => smaller(x)
+            .into_iter()
+            .map(|x| Instruction::Cpy(x, y))
+            .collect(),
+        Instruction::Jnz(x, y) => smaller(x)
+            .into_iter()
+            .map(|x| Instruction::Jnz(x, y))
+            .chain(smaller(y).into_iter().map(|y| Instruction::Jnz(x, y)))
+            .collect(),
+        Instruction::Tgl(x) => smaller(x).into_iter().map(Instruction::Tgl).collect(),
+        Instruction::Out(x) => smaller(x).into_iter().map(Instruction::Out).collect(),
+        Instruction::Inc(_) | Instruction::Dec(_) | Instruction::In(_) | Instruction::Custom(_) => {
+            vec![]
+        }
+    }
+}
+
+// Makes the program as small as it gets while 'fails' still holds, by dropping instructions and
+// moving constants towards zero.
+pub fn shrink(
+    mut program: Vec<Instruction>,
+    fails: impl Fn(&[Instruction]) -> bool,
+) -> Vec<Instruction> {
+    let mut shrunk = true;
+    while shrunk {
+        shrunk = false;
+
+        let mut pc = 0;
+        while pc < program.len() {
+            let mut candidate = program.clone();
+            candidate.remove(pc);
+            if fails(&candidate) {
+                program = candidate;
+                shrunk = true;
+            } else {
+                pc += 1;
+            }
+        }
+
+        for pc in 0..program.len() {
+            for instruction in simplified(program[pc]) {
+                let mut candidate = program.clone();
+                candidate[pc] = instruction;
+                if fails(&candidate) {
+                    program = candidate;
+                    shrunk = true;
+                    break;
+                }
+            }
+        }
+    }
+
+    program
+}
+
+// Compares the strategies on 'count' random programs. The first mismatch is shrunk before it is
+// returned.
+pub fn fuzz(seed: u64, count: usize) -> Result<(), Mismatch> {
+    let mut rng = Rng::new(seed);
+
+    for _ in 0..count {
+        let snippets = rng.range(1, 12) as usize;
+        let program = generate(&mut rng, snippets);
+        let registers = [0; 4].map(|_| rng.range(-3, 3));
+
+        if compare(&program, registers).is_some() {
+            let program = shrink(program, |p| compare(p, registers).is_some());
+            return Err(compare(&program, registers).unwrap());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generated_programs_halt() {
+        // i64 registers, as the extreme values may overflow an i32
+        let mut rng = Rng::new(1);
+        for _ in 0..200 {
+            let program = generate(&mut rng, 10);
+            let mut machine = Machine::new(program.clone(), [2, -1, 0, 3].map(i64::from));
+            machine.run();
+            assert!(
+                machine.is_halted() && machine.overflow.is_none(),
+                "{:?}",
+                program
+            );
+        }
+    }
+
+    #[test]
+    fn test_toggled_loops() {
+        // Both the starting shape and the toggled one, in add and multiply loops
+        let mut rng = Rng::new(3);
+        let mut fused = 0;
+        for _ in 0..50 {
+            let program = toggled_loop(&mut rng);
+            let mut machine = Machine::new(program.clone(), [0; 4]);
+            machine.optimize();
+            fused += machine.blocks.iter().flatten().count();
+            machine.run();
+
+            assert!(machine.toggles.iter().all(|t| t.change.is_some()));
+            assert_eq!(compare(&program, [0; 4]), None, "{:?}", program);
+        }
+        assert!(fused > 0);
+    }
+
+    #[test]
+    fn test_strategies_agree() {
+        for seed in 0..10 {
+            if let Err(mismatch) = fuzz(seed, 100) {
+                panic!("{}", mismatch);
+            }
+        }
+    }
+
+    #[test]
+    fn test_shrink() {
+        let program = generate(&mut Rng::new(7), 30);
+        let has_tgl_and_out = |p: &[Instruction]| {
+            p.iter().any(|i| matches!(i, Instruction::Tgl(_)))
+                && p.iter().any(|i| matches!(i, Instruction::Out(_)))
+        };
+        assert!(has_tgl_and_out(&program));
+
+        let shrunk = shrink(program, has_tgl_and_out);
+        assert_eq!(shrunk.len(), 2);
+        assert!(shrunk.contains(&Instruction::Tgl(val(0))));
+    }
+}
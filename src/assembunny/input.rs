@@ -0,0 +1,166 @@
+use std::collections::VecDeque;
+use std::io::{self, BufRead, StdinLock};
+
+use super::word::Word;
+use super::Machine;
+
+// Where 'in' values come from when the machine is run with 'run_with_input'. 'None' means there
+// is no more input.
+pub trait Source {
+    fn read(&mut self) -> Option<i32>;
+}
+
+// A fixed queue of test vectors
+impl Source for VecDeque<i32> {
+    fn read(&mut self) -> Option<i32> {
+        self.pop_front()
+    }
+}
+
+impl<F: FnMut() -> Option<i32>> Source for F {
+    fn read(&mut self) -> Option<i32> {
+        self()
+    }
+}
+
+// One number per line, e.g. typed into stdin. Blank lines are skipped. Input ends at the end of
+// the text or at the first line that is not a number, which is kept in 'error'.
+pub struct Lines<R> {
+    reader: R,
+    pub error: Option<String>,
+}
+
+impl<R: BufRead> Lines<R> {
+    pub fn new(reader: R) -> Self {
+        Lines {
+            reader,
+            error: None,
+        }
+    }
+}
+
+pub fn stdin() -> Lines<StdinLock<'static>> {
+    Lines::new(io::stdin().lock())
+}
+
+impl<R: BufRead> Source for Lines<R> {
+    fn read(&mut self) -> Option<i32> {
+        let mut line = String::new();
+        while line.trim().is_empty() {
+            line.clear();
+            match self.reader.read_line(&mut line) {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(e) => {
+                    self.error = Some(e.to_string());
+                    return None;
+                }
+            }
+        }
+
+        let line = line.trim();
+        match line.parse() {
+            Ok(value) => Some(value),
+            Err(_) => {
+                self.error = Some(format!("Not a number: {}", line));
+                None
+            }
+        }
+    }
+}
+
+impl<W: Word> Machine<W> {
+    // Runs until the program halts, or waits for input after the source has run out.
+    pub fn run_with_input(&mut self, source: &mut impl Source) {
+        loop {
+            if self.step() {
+                continue;
+            }
+            if !self.is_waiting() {
+                return;
+            }
+            match source.read() {
+                Some(value) => self.input.push_back(W::from_i32(value)),
+                None => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembunny::parse;
+
+    // Outputs the sum of each pair of values read, until a pair sums to zero. The second of a pair
+    // has to be positive.
+    static ADDER: &str = "in a
+in b
+cpy b c
+inc a
+dec c
+jnz c -2
+out a
+jnz a -7";
+
+    #[test]
+    fn test_queue() {
+        let mut machine = Machine::new(parse(ADDER), [0; 4]);
+        machine.run_with_input(&mut VecDeque::from([2, 3, 10, 4, -1, 1]));
+        assert_eq!(machine.output, vec![5, 14, 0]);
+        assert!(machine.is_halted());
+    }
+
+    #[test]
+    fn test_waits_when_input_runs_out() {
+        let mut machine = Machine::new(parse(ADDER), [0; 4]);
+        machine.input.extend([2, 3, 4]);
+        machine.run();
+        assert_eq!(machine.output, vec![5]);
+        assert!(machine.is_waiting());
+        assert_eq!(machine.pc, 1);
+
+        machine.input.extend([5, -1, 1]);
+        machine.run();
+        assert_eq!(machine.output, vec![5, 9, 0]);
+        assert!(machine.is_halted());
+    }
+
+    #[test]
+    fn test_closure() {
+        let mut count = 0;
+        let mut source = || {
+            count += 1;
+            Some(count)
+        };
+
+        // Outputs each value read doubled
+        let program = parse("in a\ncpy a b\ninc a\ndec b\njnz b -2\nout a\njnz 1 -6");
+        let mut machine = Machine::new(program, [0; 4]);
+        machine.run_with_input(&mut || source().filter(|&v| v <= 3));
+        assert_eq!(machine.output, vec![2, 4, 6]);
+        assert!(machine.is_waiting());
+    }
+
+    #[test]
+    fn test_lines() {
+        let mut lines = Lines::new("1\n\n  -2 \nthree\n4\n".as_bytes());
+        assert_eq!(lines.read(), Some(1));
+        assert_eq!(lines.read(), Some(-2));
+        assert_eq!(lines.read(), None);
+        assert_eq!(lines.error, Some("Not a number: three".to_string()));
+
+        let mut machine = Machine::new(parse("in a\nin b"), [0; 4]);
+        machine.run_with_input(&mut Lines::new("7\n8".as_bytes()));
+        assert_eq!(machine.registers, [7, 8, 0, 0]);
+    }
+
+    #[test]
+    fn test_skipped_into_literal() {
+        let mut machine = Machine::new(parse("in 3\nin a"), [0; 4]);
+        machine.input.push_back(1);
+        machine.run();
+        assert_eq!(machine.registers, [1, 0, 0, 0]);
+        assert_eq!(machine.skipped, vec![(0, parse("in 3")[0])]);
+    }
+}
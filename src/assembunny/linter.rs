@@ -0,0 +1,227 @@
+use std::fmt;
+
+use super::{register_name, Instruction, Operand};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LintKind {
+    UnknownInstruction(String),
+    JumpOutside(i64),
+    NeverWritten(usize),
+    WriteToLiteral,
+    UnchangedLoopCounter(usize),
+}
+
+// A problem found in the program, at a 1-based line of the source text
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lint {
+    pub line: usize,
+    pub kind: LintKind,
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: ", self.line)?;
+        match &self.kind {
+            LintKind::UnknownInstruction(e) => write!(f, "{}", e),
+            LintKind::JumpOutside(target) => {
+                write!(f, "jump to {} is outside the program", target)
+            }
+            LintKind::NeverWritten(r) => {
+                write!(
+                    f,
+                    "register {} is read but never written",
+                    register_name(*r)
+                )
+            }
+            LintKind::WriteToLiteral => write!(f, "writes into a literal and is skipped"),
+            LintKind::UnchangedLoopCounter(r) => write!(
+                f,
+                "loop counter {} never changes inside the loop",
+                register_name(*r)
+            ),
+        }
+    }
+}
+
+fn written(instruction: Instruction) -> Option<usize> {
+    match instruction {
+        Instruction::Cpy(_, Operand::Register(r))
+        | Instruction::Inc(Operand::Register(r))
+        | Instruction::Dec(Operand::Register(r))
+        | Instruction::In(Operand::Register(r)) => Some(r),
+        _ => None,
+    }
+}
+
+fn read(instruction: Instruction) -> Vec<usize> {
+    let operands = match instruction {
+        Instruction::Cpy(x, _) | Instruction::Tgl(x) | Instruction::Out(x) => vec![x],
+        Instruction::Jnz(x, y) => vec![x, y],
+        Instruction::Inc(_) | Instruction::Dec(_) | Instruction::In(_) => vec![],
+        Instruction::Custom(custom) => custom.operands().to_vec(),
+    };
+
+    operands
+        .into_iter()
+        .filter_map(|o| match o {
+            Operand::Register(r) => Some(r),
+            Operand::Value(_) => None,
+        })
+        .collect()
+}
+
+// Checks the program text for mistakes that would otherwise only show up as panics, skipped
+// instructions or endless loops when run.
+pub fn lint(input: &str) -> Vec<Lint> {
+    let mut lints = Vec::new();
+
+    // (line, instruction) per non-blank line. Lines that do not parse keep their pc, so jumps
+    // past them are measured as in the intended program.
+    let mut program = Vec::new();
+    for (idx, text) in input.lines().enumerate() {
+        if text.trim().is_empty() {
+            continue;
+        }
+        match text.parse::<Instruction>() {
+            Ok(instruction) => program.push((idx + 1, Some(instruction))),
+            Err(e) => {
+                program.push((idx + 1, None));
+                lints.push(Lint {
+                    line: idx + 1,
+                    kind: LintKind::UnknownInstruction(e),
+                });
+            }
+        }
+    }
+
+    let written_anywhere: Vec<usize> = program
+        .iter()
+        .filter_map(|&(_, i)| i.and_then(written))
+        .collect();
+    let mut reported = Vec::new();
+
+    for (pc, &(line, instruction)) in program.iter().enumerate() {
+        let Some(instruction) = instruction else {
+            continue;
+        };
+        let mut lint = |kind| lints.push(Lint { line, kind });
+
+        for r in read(instruction) {
+            if !written_anywhere.contains(&r) && !reported.contains(&r) {
+                reported.push(r);
+                lint(LintKind::NeverWritten(r));
+            }
+        }
+
+        match instruction {
+            Instruction::Cpy(_, Operand::Value(_))
+            | Instruction::Inc(Operand::Value(_))
+            | Instruction::Dec(Operand::Value(_))
+            | Instruction::In(Operand::Value(_)) => lint(LintKind::WriteToLiteral),
+            Instruction::Jnz(x, Operand::Value(offset)) if x != Operand::Value(0) => {
+                // In i64, as a jump the machine runs fine can still reach past i32
+                let target = pc as i64 + offset as i64;
+                if target < 0 || target > program.len() as i64 {
+                    lint(LintKind::JumpOutside(target));
+                }
+
+                // A 'tgl' inside the loop could still turn something into a write, and a line that
+                // does not parse could be anything
+                if let (Operand::Register(r), true) = (x, offset <= 0 && target >= 0) {
+                    let body = &program[target as usize..=pc];
+                    let changes = body.iter().any(|&(_, i)| match i {
+                        Some(i) => written(i) == Some(r) || matches!(i, Instruction::Tgl(_)),
+                        None => true,
+                    });
+                    if !changes {
+                        lint(LintKind::UnchangedLoopCounter(r));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    lints.sort_by_key(|l| l.line);
+    lints
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clean_program() {
+        assert_eq!(
+            lint("cpy 41 a\ninc a\ninc a\ndec a\njnz a 2\ndec a\n"),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_lints() {
+        let input = "cpy 3 a
+mul a b
+jnz a -5
+cpy a 2
+
+inc b
+jnz c -1
+jnz b 6
+dec a
+jnz a -2
+out d
+jnz 0 -100";
+
+        let lints: Vec<String> = lint(input).iter().map(|l| l.to_string()).collect();
+        assert_eq!(
+            lints,
+            vec![
+                "line 2: Unknown op code: mul a b",
+                "line 3: jump to -3 is outside the program",
+                "line 4: writes into a literal and is skipped",
+                "line 7: register c is read but never written",
+                "line 7: loop counter c never changes inside the loop",
+                "line 8: jump to 12 is outside the program",
+                "line 11: register d is read but never written",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unknown_instructions_keep_their_pc() {
+        let lints: Vec<String> = lint("inc a\nmul a b\njnz a -2")
+            .iter()
+            .map(|l| l.to_string())
+            .collect();
+        assert_eq!(lints, vec!["line 2: Unknown op code: mul a b"]);
+
+        let lints: Vec<String> = lint("cpy 1 a\ninc b\nmul a b\njnz a -2\njnz a 2")
+            .iter()
+            .map(|l| l.to_string())
+            .collect();
+        assert_eq!(
+            lints,
+            vec![
+                "line 3: Unknown op code: mul a b",
+                "line 5: jump to 6 is outside the program",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_jump_past_i32() {
+        assert_eq!(
+            lint("inc a\njnz a 2147483647"),
+            vec![Lint {
+                line: 2,
+                kind: LintKind::JumpOutside(2147483648),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_tgl_can_change_loop_counter() {
+        assert_eq!(lint("cpy 1 a\ntgl 1\njnz a -1"), vec![]);
+    }
+}
@@ -0,0 +1,582 @@
+// Instructions as registered opcodes. An opcode says how it is written, what it does and what
+// 'tgl' turns it into, and an instruction set is the opcodes a puzzle allows. The built-in opcodes
+// parse into the 'Instruction' variants the rest of the tooling understands. Any other opcode is
+// kept as 'Instruction::Custom' and only runs through its own hooks.
+
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+use super::{Instruction, Operand};
+
+// How execution continues after an instruction
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Flow {
+    Next,
+    // Relative to the instruction
+    Jump(i64),
+    // The instruction is invalid, e.g. a write into a literal, and is skipped
+    Skip,
+    // A register would overflow. The machine stops at the instruction.
+    Overflow,
+    // Nothing to read. The machine stays at the instruction.
+    Wait,
+}
+
+// What an instruction can see and change while it executes, whatever the register width
+pub trait Cpu {
+    // None if the value does not fit an i64
+    fn get(&self, operand: Operand) -> Option<i64>;
+    // False if the value does not fit the register
+    fn set(&mut self, register: usize, value: i64) -> bool;
+    // Copies at full width, so it works for values 'get' cannot return
+    fn copy(&mut self, from: Operand, register: usize);
+    // False on overflow
+    fn add(&mut self, register: usize, step: i32) -> bool;
+    fn output(&mut self, operand: Operand);
+    // False if there is nothing to read
+    fn input(&mut self, register: usize) -> bool;
+    // Toggles the instruction 'offset' away from the executing one
+    fn toggle(&mut self, offset: i64);
+}
+
+pub trait Opcode: Sync {
+    fn name(&self) -> &'static str;
+    // One or two operands
+    fn arity(&self) -> usize;
+
+    // The arguments come already checked against the arity
+    fn parse(&self, args: &[&str]) -> Result<Vec<Operand>, String> {
+        args.iter().map(|arg| arg.parse()).collect()
+    }
+
+    fn execute(&self, operands: &[Operand], cpu: &mut dyn Cpu) -> Flow;
+
+    // What 'tgl' turns the instruction into. By default the rule of day 23: 'inc' for one
+    // operand, 'jnz' for two.
+    fn toggled(&self, operands: &[Operand]) -> Instruction {
+        match *operands {
+            [x] => Instruction::Inc(x),
+            [x, y] => Instruction::Jnz(x, y),
+            _ => panic!("{} takes {} operands", self.name(), self.arity()),
+        }
+    }
+
+    // The variant the optimizer and the other backends know, for the built-in opcodes only
+    fn builtin(&self, _operands: &[Operand]) -> Option<Instruction> {
+        None
+    }
+}
+
+pub struct Cpy;
+pub struct Inc;
+pub struct Dec;
+pub struct Jnz;
+pub struct Tgl;
+pub struct Out;
+pub struct In;
+
+// Instructions writing into a literal can only come from 'tgl'. The puzzle says to skip them.
+fn step(cpu: &mut dyn Cpu, operand: Operand, step: i32) -> Flow {
+    match operand {
+        Operand::Register(x) if cpu.add(x, step) => Flow::Next,
+        Operand::Register(_) => Flow::Overflow,
+        Operand::Value(_) => Flow::Skip,
+    }
+}
+
+impl Opcode for Cpy {
+    fn name(&self) -> &'static str {
+        "cpy"
+    }
+
+    fn arity(&self) -> usize {
+        2
+    }
+
+    fn execute(&self, operands: &[Operand], cpu: &mut dyn Cpu) -> Flow {
+        match operands[1] {
+            Operand::Register(y) => {
+                cpu.copy(operands[0], y);
+                Flow::Next
+            }
+            Operand::Value(_) => Flow::Skip,
+        }
+    }
+
+    fn builtin(&self, operands: &[Operand]) -> Option<Instruction> {
+        Some(Instruction::Cpy(operands[0], operands[1]))
+    }
+}
+
+impl Opcode for Inc {
+    fn name(&self) -> &'static str {
+        "inc"
+    }
+
+    fn arity(&self) -> usize {
+        1
+    }
+
+    fn execute(&self, operands: &[Operand], cpu: &mut dyn Cpu) -> Flow {
+        step(cpu, operands[0], 1)
+    }
+
+    fn toggled(&self, operands: &[Operand]) -> Instruction {
+        Instruction::Dec(operands[0])
+    }
+
+    fn builtin(&self, operands: &[Operand]) -> Option<Instruction> {
+        Some(Instruction::Inc(operands[0]))
+    }
+}
+
+impl Opcode for Dec {
+    fn name(&self) -> &'static str {
+        "dec"
+    }
+
+    fn arity(&self) -> usize {
+        1
+    }
+
+    fn execute(&self, operands: &[Operand], cpu: &mut dyn Cpu) -> Flow {
+        step(cpu, operands[0], -1)
+    }
+
+    fn builtin(&self, operands: &[Operand]) -> Option<Instruction> {
+        Some(Instruction::Dec(operands[0]))
+    }
+}
+
+impl Opcode for Jnz {
+    fn name(&self) -> &'static str {
+        "jnz"
+    }
+
+    fn arity(&self) -> usize {
+        2
+    }
+
+    fn execute(&self, operands: &[Operand], cpu: &mut dyn Cpu) -> Flow {
+        if cpu.get(operands[0]) == Some(0) {
+            return Flow::Next;
+        }
+        // Offsets too large for an i64 leave the program either way
+        Flow::Jump(cpu.get(operands[1]).unwrap_or(i64::MAX))
+    }
+
+    fn toggled(&self, operands: &[Operand]) -> Instruction {
+        Instruction::Cpy(operands[0], operands[1])
+    }
+
+    fn builtin(&self, operands: &[Operand]) -> Option<Instruction> {
+        Some(Instruction::Jnz(operands[0], operands[1]))
+    }
+}
+
+impl Opcode for Tgl {
+    fn name(&self) -> &'static str {
+        "tgl"
+    }
+
+    fn arity(&self) -> usize {
+        1
+    }
+
+    fn execute(&self, operands: &[Operand], cpu: &mut dyn Cpu) -> Flow {
+        cpu.toggle(cpu.get(operands[0]).unwrap_or(i64::MAX));
+        Flow::Next
+    }
+
+    fn builtin(&self, operands: &[Operand]) -> Option<Instruction> {
+        Some(Instruction::Tgl(operands[0]))
+    }
+}
+
+impl Opcode for Out {
+    fn name(&self) -> &'static str {
+        "out"
+    }
+
+    fn arity(&self) -> usize {
+        1
+    }
+
+    fn execute(&self, operands: &[Operand], cpu: &mut dyn Cpu) -> Flow {
+        cpu.output(operands[0]);
+        Flow::Next
+    }
+
+    fn builtin(&self, operands: &[Operand]) -> Option<Instruction> {
+        Some(Instruction::Out(operands[0]))
+    }
+}
+
+impl Opcode for In {
+    fn name(&self) -> &'static str {
+        "in"
+    }
+
+    fn arity(&self) -> usize {
+        1
+    }
+
+    fn execute(&self, operands: &[Operand], cpu: &mut dyn Cpu) -> Flow {
+        match operands[0] {
+            Operand::Register(x) if cpu.input(x) => Flow::Next,
+            Operand::Register(_) => Flow::Wait,
+            Operand::Value(_) => Flow::Skip,
+        }
+    }
+
+    fn builtin(&self, operands: &[Operand]) -> Option<Instruction> {
+        Some(Instruction::In(operands[0]))
+    }
+}
+
+// An instruction of an opcode that is not built in. Only the first 'arity' operands are used.
+#[derive(Copy, Clone)]
+pub struct Custom {
+    pub opcode: &'static dyn Opcode,
+    pub operands: [Operand; 2],
+}
+
+impl Custom {
+    pub fn operands(&self) -> &[Operand] {
+        &self.operands[..self.opcode.arity()]
+    }
+}
+
+// Opcodes are told apart by name, as an instruction set holds one opcode per name
+impl PartialEq for Custom {
+    fn eq(&self, other: &Self) -> bool {
+        self.opcode.name() == other.opcode.name() && self.operands() == other.operands()
+    }
+}
+
+impl Eq for Custom {}
+
+impl Hash for Custom {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.opcode.name().hash(state);
+        self.operands().hash(state);
+    }
+}
+
+impl fmt::Debug for Custom {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{:?}", self.opcode.name(), self.operands())
+    }
+}
+
+// The instruction for an opcode and its operands, built in or not
+pub fn instruction(opcode: &'static dyn Opcode, operands: &[Operand]) -> Instruction {
+    opcode.builtin(operands).unwrap_or_else(|| {
+        let mut padded = [Operand::Value(0); 2];
+        padded[..operands.len()].copy_from_slice(operands);
+        Instruction::Custom(Custom {
+            opcode,
+            operands: padded,
+        })
+    })
+}
+
+#[derive(Clone, Default)]
+pub struct InstructionSet {
+    opcodes: Vec<&'static dyn Opcode>,
+}
+
+impl InstructionSet {
+    pub fn day12() -> Self {
+        InstructionSet {
+            opcodes: vec![&Cpy, &Inc, &Dec, &Jnz],
+        }
+    }
+
+    pub fn day23() -> Self {
+        let mut set = Self::day12();
+        set.register(&Tgl);
+        set
+    }
+
+    pub fn day25() -> Self {
+        let mut set = Self::day12();
+        set.register(&Out);
+        set
+    }
+
+    // Every built-in opcode, 'in' included
+    pub fn builtin() -> Self {
+        let mut set = Self::day23();
+        set.register(&Out);
+        set.register(&In);
+        set
+    }
+
+    // Replaces the opcode of the same name, if any
+    pub fn register(&mut self, opcode: &'static dyn Opcode) {
+        assert!(
+            (1..=2).contains(&opcode.arity()),
+            "{} must take one or two operands",
+            opcode.name()
+        );
+        match self.find(opcode.name()) {
+            Some(i) => self.opcodes[i] = opcode,
+            None => self.opcodes.push(opcode),
+        }
+    }
+
+    fn find(&self, name: &str) -> Option<usize> {
+        self.opcodes.iter().position(|opcode| opcode.name() == name)
+    }
+
+    pub fn parse_line(&self, line: &str) -> Result<Instruction, String> {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        let opcode = match parts.split_first() {
+            Some((name, args)) => self
+                .find(name)
+                .map(|i| self.opcodes[i])
+                .filter(|opcode| opcode.arity() == args.len()),
+            None => None,
+        };
+        let Some(opcode) = opcode else {
+            return Err(format!("Unknown op code: {}", line));
+        };
+
+        let operands = opcode.parse(&parts[1..])?;
+        if operands.len() != opcode.arity() {
+            return Err(format!(
+                "{} parsed into {} operands: {}",
+                opcode.name(),
+                operands.len(),
+                line
+            ));
+        }
+        Ok(instruction(opcode, &operands))
+    }
+
+    pub fn parse(&self, input: &str) -> Result<Vec<Instruction>, String> {
+        input
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .map(|l| self.parse_line(l))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembunny::threaded::Threaded;
+    use crate::assembunny::{parse, Machine};
+
+    // mul x y: y *= x, toggled into 'div'
+    struct Mul;
+    struct Div;
+
+    impl Opcode for Mul {
+        fn name(&self) -> &'static str {
+            "mul"
+        }
+
+        fn arity(&self) -> usize {
+            2
+        }
+
+        fn execute(&self, operands: &[Operand], cpu: &mut dyn Cpu) -> Flow {
+            let (Some(x), Operand::Register(y)) = (cpu.get(operands[0]), operands[1]) else {
+                return Flow::Skip;
+            };
+            match cpu.get(operands[1]).and_then(|y| y.checked_mul(x)) {
+                Some(product) if cpu.set(y, product) => Flow::Next,
+                _ => Flow::Overflow,
+            }
+        }
+
+        fn toggled(&self, operands: &[Operand]) -> Instruction {
+            instruction(&Div, operands)
+        }
+    }
+
+    impl Opcode for Div {
+        fn name(&self) -> &'static str {
+            "div"
+        }
+
+        fn arity(&self) -> usize {
+            2
+        }
+
+        fn execute(&self, operands: &[Operand], cpu: &mut dyn Cpu) -> Flow {
+            let (Some(x), Operand::Register(y)) = (cpu.get(operands[0]), operands[1]) else {
+                return Flow::Skip;
+            };
+            match cpu.get(operands[1]).and_then(|y| y.checked_div(x)) {
+                Some(quotient) if cpu.set(y, quotient) => Flow::Next,
+                _ => Flow::Skip,
+            }
+        }
+
+        fn toggled(&self, operands: &[Operand]) -> Instruction {
+            instruction(&Mul, operands)
+        }
+    }
+
+    // halt ok: leaves the program. Its only argument is a word, not an operand.
+    struct Halt;
+
+    impl Opcode for Halt {
+        fn name(&self) -> &'static str {
+            "halt"
+        }
+
+        fn arity(&self) -> usize {
+            1
+        }
+
+        fn parse(&self, args: &[&str]) -> Result<Vec<Operand>, String> {
+            match args {
+                ["ok"] => Ok(vec![Operand::Value(0)]),
+                _ => Err(format!("halt takes 'ok': {}", args.join(" "))),
+            }
+        }
+
+        fn execute(&self, _operands: &[Operand], _cpu: &mut dyn Cpu) -> Flow {
+            Flow::Jump(i64::MIN)
+        }
+    }
+
+    fn custom_set() -> InstructionSet {
+        let mut set = InstructionSet::day23();
+        set.register(&Mul);
+        set.register(&Halt);
+        set
+    }
+
+    fn run(program: Vec<Instruction>, optimize: bool) -> Machine<i64> {
+        let mut machine = Machine::new(program, [0; 4]);
+        if optimize {
+            machine.optimize();
+        }
+        machine.run();
+        machine
+    }
+
+    #[test]
+    fn test_presets() {
+        let input = "cpy 2 a\ntgl a\ninc a\ndec a\njnz a -3";
+        assert_eq!(InstructionSet::day23().parse(input), Ok(parse(input)));
+        assert_eq!(
+            InstructionSet::day12().parse(input),
+            Err("Unknown op code: tgl a".to_string())
+        );
+        assert_eq!(InstructionSet::day25().parse("out a"), Ok(parse("out a")));
+        assert_eq!(
+            InstructionSet::day25().parse("out a b"),
+            Err("Unknown op code: out a b".to_string())
+        );
+        assert_eq!(
+            InstructionSet::day25().parse("in a"),
+            Err("Unknown op code: in a".to_string())
+        );
+        assert_eq!(InstructionSet::builtin().parse("in a"), Ok(parse("in a")));
+    }
+
+    #[test]
+    fn test_custom_opcodes() {
+        let set = custom_set();
+        let program = set
+            .parse("cpy 6 a\ncpy 3 b\nmul 7 a\ndec b\njnz b -2\nhalt ok\ninc c")
+            .unwrap();
+        assert_eq!(program[2].to_string(), "mul 7 a");
+        assert_eq!(program[5].to_string(), "halt 0");
+
+        for optimize in [false, true] {
+            let machine = run(program.clone(), optimize);
+            assert_eq!(machine.registers, [6 * 343, 0, 0, 0]);
+            assert_eq!(machine.cycles, 12);
+        }
+
+        assert_eq!(
+            Machine::new(program.clone(), [0; 4]).to_bytecode(),
+            Err("No bytecode for mul 7 a".to_string())
+        );
+
+        let mut threaded = Threaded::new(program.clone(), [0; 4]);
+        threaded.run();
+        assert_eq!(threaded.state.registers, [6 * 343, 0, 0, 0]);
+
+        assert_eq!(
+            set.parse("halt now"),
+            Err("halt takes 'ok': now".to_string())
+        );
+        assert_eq!(
+            set.parse("mul 2"),
+            Err("Unknown op code: mul 2".to_string())
+        );
+        assert!(InstructionSet::day23().parse("mul 2 a").is_err());
+    }
+
+    #[test]
+    fn test_registering_replaces_by_name() {
+        // 'inc' now multiplies
+        struct Double;
+
+        impl Opcode for Double {
+            fn name(&self) -> &'static str {
+                "inc"
+            }
+
+            fn arity(&self) -> usize {
+                1
+            }
+
+            fn execute(&self, operands: &[Operand], cpu: &mut dyn Cpu) -> Flow {
+                Mul.execute(&[Operand::Value(2), operands[0]], cpu)
+            }
+        }
+
+        let mut set = InstructionSet::day12();
+        set.register(&Double);
+        let program = set.parse("cpy 3 a\ninc a\ninc a").unwrap();
+        assert_eq!(run(program, true).registers, [12, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_toggling_custom_opcodes() {
+        let set = custom_set();
+
+        // 'mul 4 a' turns into 'div 4 a', and back
+        let mut machine = run(set.parse("cpy 48 a\ntgl 1\nmul 4 a").unwrap(), false);
+        assert_eq!(machine.registers, [12, 0, 0, 0]);
+        assert_eq!(machine.program[2].to_string(), "div 4 a");
+        assert_eq!(machine.program[2].toggled().to_string(), "mul 4 a");
+        assert_eq!(machine.toggles.len(), 1);
+
+        // Without its own rule, 'halt' turns into 'inc' like any one-operand instruction
+        machine = run(set.parse("tgl 1\nhalt ok\ninc a").unwrap(), false);
+        assert_eq!(machine.registers, [1, 0, 0, 0]);
+        assert_eq!(
+            machine.skipped,
+            vec![(1, Instruction::Inc(Operand::Value(0)))]
+        );
+    }
+
+    #[test]
+    fn test_custom_overflow_and_skips() {
+        let set = custom_set();
+
+        let program = set.parse("cpy 65536 a\nmul a a\nmul a a\ninc b").unwrap();
+        let mut machine = Machine::new(program.clone(), [0; 4]);
+        assert_eq!(
+            machine.run_checked(),
+            Err("Overflow at 1: mul a a".to_string())
+        );
+        assert_eq!(run(program.clone(), false).overflow, Some((2, program[2])));
+
+        // Writes into a literal are skipped like those of the built-in opcodes
+        let machine = run(set.parse("mul 2 3\ninc a").unwrap(), false);
+        assert_eq!(machine.registers, [1, 0, 0, 0]);
+        assert_eq!(machine.skipped.len(), 1);
+    }
+}
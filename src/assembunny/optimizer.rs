@@ -0,0 +1,402 @@
+use super::word::Word;
+use super::{Instruction, Operand};
+
+// Length of the longest block shape, i.e. how far back a block can start and still include a
+// given instruction.
+const MAX_BLOCK_LEN: usize = 6;
+
+// A loop shape that can be executed as a single arithmetic step. The counter registers are
+// stepped with +1 or -1 until they reach zero.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Block {
+    // inc/dec target, inc/dec counter, jnz counter -2
+    Add {
+        target: usize,
+        target_step: i32,
+        counter: usize,
+        counter_step: i32,
+    },
+    // cpy source inner, (add loop with inner as counter), inc/dec outer, jnz outer -5
+    Mul {
+        source: Operand,
+        target: usize,
+        target_step: i32,
+        inner: usize,
+        inner_step: i32,
+        outer: usize,
+        outer_step: i32,
+    },
+}
+
+// Number of loop iterations until 'value' stepped by 'step' reaches zero, if it ever does
+// without wrapping around.
+fn iterations<W: Word>(value: &W, step: i32) -> Option<W> {
+    let n = value.checked_mul(&W::from_i32(-step))?;
+    n.is_positive().then_some(n)
+}
+
+impl Block {
+    // Number of instructions replaced. A block is never empty.
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        match self {
+            Block::Add { .. } => 3,
+            Block::Mul { .. } => 6,
+        }
+    }
+
+    // Whether the block starting at 'start' was built from the instruction at 'idx'.
+    pub fn depends_on(&self, start: usize, idx: usize) -> bool {
+        (start..start + self.len()).contains(&idx)
+    }
+
+    pub fn target(&self) -> usize {
+        match *self {
+            Block::Add { target, .. } | Block::Mul { target, .. } => target,
+        }
+    }
+
+    // Applies the whole loop to the registers. Nothing is touched unless it is 'Done'.
+    pub fn execute<W: Word>(&self, registers: &mut [W; 4]) -> Execution {
+        // Loops too long to count in a usize are counted as usize::MAX instructions
+        let count = |n: &W| n.to_usize().unwrap_or(usize::MAX);
+
+        let (target, target_step, n, instructions) = match *self {
+            Block::Add {
+                target,
+                target_step,
+                counter,
+                counter_step,
+            } => {
+                let Some(n) = iterations(&registers[counter], counter_step) else {
+                    return Execution::Interpret;
+                };
+                let instructions = count(&n).saturating_mul(3);
+                (target, target_step, Some(n), instructions)
+            }
+            Block::Mul {
+                source,
+                target,
+                target_step,
+                inner_step,
+                outer,
+                outer_step,
+                ..
+            } => {
+                let source = match source {
+                    Operand::Register(r) => registers[r].clone(),
+                    Operand::Value(v) => W::from_i32(v),
+                };
+                let (Some(n_inner), Some(n_outer)) = (
+                    iterations(&source, inner_step),
+                    iterations(&registers[outer], outer_step),
+                ) else {
+                    return Execution::Interpret;
+                };
+                // The 'cpy', the add loop, the 'inc'/'dec' and the 'jnz' per outer iteration
+                let instructions = count(&n_outer)
+                    .saturating_mul(count(&n_inner).saturating_mul(3).saturating_add(3));
+                (
+                    target,
+                    target_step,
+                    n_inner.checked_mul(&n_outer),
+                    instructions,
+                )
+            }
+        };
+
+        let Some(total) = n
+            .and_then(|n| n.checked_mul(&W::from_i32(target_step)))
+            .and_then(|added| registers[target].checked_add(&added))
+        else {
+            return Execution::Overflow;
+        };
+
+        registers[target] = total;
+        match *self {
+            Block::Add { counter, .. } => registers[counter] = W::from_i32(0),
+            Block::Mul { inner, outer, .. } => {
+                registers[inner] = W::from_i32(0);
+                registers[outer] = W::from_i32(0);
+            }
+        }
+
+        Execution::Done(instructions)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Execution {
+    // With the number of instructions the loop would have taken when interpreted
+    Done(usize),
+    // The loop would not terminate normally, so it has to be interpreted instead
+    Interpret,
+    // Stepping the target would overflow somewhere inside the loop
+    Overflow,
+}
+
+fn register_step(instruction: Instruction) -> Option<(usize, i32)> {
+    match instruction {
+        Instruction::Inc(Operand::Register(r)) => Some((r, 1)),
+        Instruction::Dec(Operand::Register(r)) => Some((r, -1)),
+        _ => None,
+    }
+}
+
+fn is_jump_back(instruction: Instruction, register: usize, offset: i32) -> bool {
+    instruction == Instruction::Jnz(Operand::Register(register), Operand::Value(-offset))
+}
+
+fn find_add(program: &[Instruction]) -> Option<Block> {
+    let [first, second, jump, ..] = program else {
+        return None;
+    };
+    let (first, second) = (register_step(*first)?, register_step(*second)?);
+
+    let ((target, target_step), (counter, counter_step)) = if is_jump_back(*jump, second.0, 2) {
+        (first, second)
+    } else if is_jump_back(*jump, first.0, 2) {
+        (second, first)
+    } else {
+        return None;
+    };
+
+    (target != counter).then_some(Block::Add {
+        target,
+        target_step,
+        counter,
+        counter_step,
+    })
+}
+
+fn find_mul(program: &[Instruction]) -> Option<Block> {
+    let [Instruction::Cpy(source, Operand::Register(copied)), _, _, _, outer_step, jump, ..] =
+        program
+    else {
+        return None;
+    };
+    let Block::Add {
+        target,
+        target_step,
+        counter: inner,
+        counter_step: inner_step,
+    } = find_add(&program[1..])?
+    else {
+        return None;
+    };
+    let (outer, outer_step) = register_step(*outer_step)?;
+
+    let distinct = *copied == inner && outer != inner && outer != target;
+    let stable_source = match *source {
+        Operand::Register(r) => r != target && r != inner && r != outer,
+        Operand::Value(_) => true,
+    };
+
+    (distinct && stable_source && is_jump_back(*jump, outer, 5)).then_some(Block::Mul {
+        source: *source,
+        target,
+        target_step,
+        inner,
+        inner_step,
+        outer,
+        outer_step,
+    })
+}
+
+fn find_block(program: &[Instruction]) -> Option<Block> {
+    find_mul(program).or_else(|| find_add(program))
+}
+
+// Finds the fusable loop, if any, starting at each instruction of the program.
+pub fn find_blocks(program: &[Instruction]) -> Vec<Option<Block>> {
+    (0..program.len())
+        .map(|pc| find_block(&program[pc..]))
+        .collect()
+}
+
+// Brings the blocks up to date after the instruction at 'changed' was rewritten by 'tgl'. Blocks
+// built from it are thrown away, and every start position that could now form a block over it is
+// searched again.
+pub fn update_blocks(blocks: &mut [Option<Block>], program: &[Instruction], changed: usize) {
+    for start in changed.saturating_sub(MAX_BLOCK_LEN - 1)..=changed {
+        if blocks[start].is_some_and(|block| block.depends_on(start, changed)) {
+            blocks[start] = None;
+        }
+
+        if blocks[start].is_none() {
+            blocks[start] = find_block(&program[start..]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembunny::{parse, Machine};
+
+    #[test]
+    fn test_find_blocks() {
+        let blocks = find_blocks(&parse(
+            "cpy b c
+inc a
+dec c
+jnz c -2
+dec d
+jnz d -5
+inc d
+inc a
+jnz d -2",
+        ));
+
+        assert_eq!(
+            blocks[0],
+            Some(Block::Mul {
+                source: Operand::Register(1),
+                target: 0,
+                target_step: 1,
+                inner: 2,
+                inner_step: -1,
+                outer: 3,
+                outer_step: -1,
+            })
+        );
+        assert_eq!(
+            blocks[1],
+            Some(Block::Add {
+                target: 0,
+                target_step: 1,
+                counter: 2,
+                counter_step: -1,
+            })
+        );
+        assert_eq!(
+            blocks[6],
+            Some(Block::Add {
+                target: 0,
+                target_step: 1,
+                counter: 3,
+                counter_step: 1,
+            })
+        );
+        assert_eq!(blocks.iter().flatten().count(), 3);
+    }
+
+    #[test]
+    fn test_non_loops_are_not_fused() {
+        // Counter is the target, the jump goes elsewhere, the source changes inside the loop
+        for input in [
+            "inc a\ndec a\njnz a -2",
+            "inc a\ndec c\njnz c -3",
+            "cpy a c\ninc a\ndec c\njnz c -2\ndec d\njnz d -5",
+        ] {
+            assert_eq!(find_blocks(&parse(input))[0], None, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_update_blocks() {
+        let mut program = parse(
+            "cpy 3 c
+jnz 4 d
+inc a
+inc d
+jnz d -2
+inc c
+jnz c -5",
+        );
+        let mut blocks = find_blocks(&program);
+        assert!(matches!(
+            blocks[2],
+            Some(Block::Add {
+                counter_step: 1,
+                ..
+            })
+        ));
+
+        // Toggling the whole tail turns it into 'a += 3 * 4'
+        for idx in [1, 3, 5] {
+            program[idx] = program[idx].toggled();
+            update_blocks(&mut blocks, &program, idx);
+            assert_eq!(blocks, find_blocks(&program), "after toggling {}", idx);
+        }
+
+        assert!(matches!(blocks[1], Some(Block::Mul { .. })));
+        assert!(matches!(
+            blocks[2],
+            Some(Block::Add {
+                counter_step: -1,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_optimized_matches_interpreter_with_tgl() {
+        // Computes a! + 3 * 4 by toggling its own tail
+        let program = parse(include_str!("fixtures/factorial.txt"));
+
+        let mut plain = Machine::new(program.clone(), [7, 0, 0, 0]);
+        plain.run();
+
+        let mut optimized = Machine::new(program, [7, 0, 0, 0]);
+        optimized.optimize();
+        optimized.run();
+
+        assert_eq!(plain.registers[0], 5040 + 12);
+        assert_eq!(optimized.registers, plain.registers);
+        assert_eq!(optimized.program, plain.program);
+    }
+
+    #[test]
+    fn test_execute_falls_back_on_non_terminating_counter() {
+        let block = Block::Add {
+            target: 0,
+            target_step: 1,
+            counter: 1,
+            counter_step: -1,
+        };
+        let mut registers = [5, 0, 0, 0];
+        assert_eq!(block.execute(&mut registers), Execution::Interpret);
+        assert_eq!(registers, [5, 0, 0, 0]);
+
+        registers[1] = 3;
+        assert_eq!(block.execute(&mut registers), Execution::Done(9));
+        assert_eq!(registers, [8, 0, 0, 0]);
+
+        registers[0] = i32::MAX - 1;
+        registers[1] = 3;
+        assert_eq!(block.execute(&mut registers), Execution::Overflow);
+        assert_eq!(registers, [i32::MAX - 1, 3, 0, 0]);
+    }
+
+    #[test]
+    fn test_optimized_matches_interpreter() {
+        let program = parse(
+            "cpy 7 b
+cpy 5 d
+cpy b c
+inc a
+dec c
+jnz c -2
+dec d
+jnz d -5
+cpy -4 c
+dec a
+inc c
+jnz c -2
+dec b
+jnz b -12",
+        );
+
+        let mut plain = Machine::new(program.clone(), [0; 4]);
+        plain.run();
+
+        let mut optimized = Machine::new(program, [0; 4]);
+        optimized.optimize();
+        optimized.run();
+
+        assert_eq!(plain.registers[0], 112);
+        assert_eq!(optimized.registers, plain.registers);
+        assert_eq!(optimized.cycles, plain.cycles);
+    }
+}
@@ -0,0 +1,279 @@
+use std::collections::VecDeque;
+
+use super::Machine;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Undecided,
+    Matched,
+    // With the index of the first value that did not match
+    Mismatched(usize),
+}
+
+// Where 'out' values go when the machine is run with 'run_with'. Anything but 'Undecided' ends the
+// run.
+pub trait Sink {
+    fn write(&mut self, value: i32) -> Verdict;
+}
+
+impl Sink for Vec<i32> {
+    fn write(&mut self, value: i32) -> Verdict {
+        self.push(value);
+        Verdict::Undecided
+    }
+}
+
+impl<F: FnMut(i32) -> Verdict> Sink for F {
+    fn write(&mut self, value: i32) -> Verdict {
+        self(value)
+    }
+}
+
+// Keeps only the latest 'capacity' values, for programs that output forever
+pub struct Bounded {
+    pub values: VecDeque<i32>,
+    capacity: usize,
+}
+
+impl Bounded {
+    pub fn new(capacity: usize) -> Self {
+        Bounded {
+            values: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+}
+
+impl Sink for Bounded {
+    fn write(&mut self, value: i32) -> Verdict {
+        if self.values.len() == self.capacity {
+            self.values.pop_front();
+        }
+        self.values.push_back(value);
+        Verdict::Undecided
+    }
+}
+
+// 0, 1, 0, 1... matched once 'length' values have been seen
+pub struct Alternating {
+    seen: usize,
+    length: usize,
+}
+
+impl Alternating {
+    pub fn new(length: usize) -> Self {
+        Alternating { seen: 0, length }
+    }
+}
+
+impl Sink for Alternating {
+    fn write(&mut self, value: i32) -> Verdict {
+        if value != (self.seen % 2) as i32 {
+            return Verdict::Mismatched(self.seen);
+        }
+
+        self.seen += 1;
+        if self.seen == self.length {
+            Verdict::Matched
+        } else {
+            Verdict::Undecided
+        }
+    }
+}
+
+// The first 'period' values repeating over and over, matched once 'length' values have been seen
+pub struct Periodic {
+    pattern: Vec<i32>,
+    period: usize,
+    seen: usize,
+    length: usize,
+}
+
+impl Periodic {
+    pub fn new(period: usize, length: usize) -> Self {
+        Periodic {
+            pattern: Vec::with_capacity(period),
+            period,
+            seen: 0,
+            length,
+        }
+    }
+}
+
+impl Sink for Periodic {
+    fn write(&mut self, value: i32) -> Verdict {
+        if self.pattern.len() < self.period {
+            self.pattern.push(value);
+        } else if value != self.pattern[self.seen % self.period] {
+            return Verdict::Mismatched(self.seen);
+        }
+
+        self.seen += 1;
+        if self.seen == self.length {
+            Verdict::Matched
+        } else {
+            Verdict::Undecided
+        }
+    }
+}
+
+// Matched as soon as all of 'expected' has been seen
+pub struct Prefix {
+    expected: Vec<i32>,
+    seen: usize,
+}
+
+impl Prefix {
+    pub fn new(expected: Vec<i32>) -> Self {
+        Prefix { expected, seen: 0 }
+    }
+}
+
+impl Sink for Prefix {
+    fn write(&mut self, value: i32) -> Verdict {
+        if self.expected.get(self.seen) != Some(&value) {
+            return Verdict::Mismatched(self.seen);
+        }
+
+        self.seen += 1;
+        if self.seen == self.expected.len() {
+            Verdict::Matched
+        } else {
+            Verdict::Undecided
+        }
+    }
+}
+
+// Steps the machine only as far as needed for the next 'out' value
+pub struct Outputs<'a> {
+    machine: &'a mut Machine,
+}
+
+impl Iterator for Outputs<'_> {
+    type Item = i32;
+
+    fn next(&mut self) -> Option<i32> {
+        while self.machine.output.is_empty() {
+            if !self.machine.step() {
+                return None;
+            }
+        }
+
+        Some(self.machine.output.remove(0))
+    }
+}
+
+impl Machine {
+    // The 'out' values as they are produced. They are taken out of 'output' on the way.
+    pub fn outputs(&mut self) -> Outputs<'_> {
+        Outputs { machine: self }
+    }
+
+    // Runs until the program halts or the sink comes to a verdict, which is then returned.
+    pub fn run_with(&mut self, sink: &mut impl Sink) -> Verdict {
+        for value in self.outputs() {
+            let verdict = sink.write(value);
+            if verdict != Verdict::Undecided {
+                return verdict;
+            }
+        }
+
+        Verdict::Undecided
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembunny::parse;
+
+    // Outputs 3, 2, 1, 0 and halts
+    static COUNTDOWN: &str = "cpy 3 a
+out a
+dec a
+jnz a -2
+out a";
+
+    // Outputs 0, 1, 0, 1... forever
+    static CLOCK: &str = "out b
+inc b
+out b
+dec b
+jnz 1 -4";
+
+    fn machine(input: &str) -> Machine {
+        Machine::new(parse(input), [0; 4])
+    }
+
+    #[test]
+    fn test_outputs() {
+        assert_eq!(
+            machine(COUNTDOWN).outputs().collect::<Vec<_>>(),
+            vec![3, 2, 1, 0]
+        );
+
+        let mut clock = machine(CLOCK);
+        assert_eq!(
+            clock.outputs().take(5).collect::<Vec<_>>(),
+            vec![0, 1, 0, 1, 0]
+        );
+        assert_eq!(clock.pc, 1);
+        assert!(clock.output.is_empty());
+    }
+
+    #[test]
+    fn test_sinks() {
+        let mut values = Vec::new();
+        assert_eq!(machine(COUNTDOWN).run_with(&mut values), Verdict::Undecided);
+        assert_eq!(values, vec![3, 2, 1, 0]);
+
+        let mut bounded = Bounded::new(2);
+        machine(COUNTDOWN).run_with(&mut bounded);
+        assert_eq!(bounded.values, vec![1, 0]);
+
+        let mut sum = 0;
+        let mut until_ten = |value| {
+            sum += value;
+            if sum >= 10 {
+                Verdict::Matched
+            } else {
+                Verdict::Undecided
+            }
+        };
+        assert_eq!(machine(CLOCK).run_with(&mut until_ten), Verdict::Matched);
+        assert_eq!(sum, 10);
+    }
+
+    #[test]
+    fn test_matchers() {
+        let mut clock = machine(CLOCK);
+        assert_eq!(clock.run_with(&mut Alternating::new(100)), Verdict::Matched);
+        assert_eq!(
+            machine(COUNTDOWN).run_with(&mut Alternating::new(100)),
+            Verdict::Mismatched(0)
+        );
+
+        assert_eq!(
+            machine(CLOCK).run_with(&mut Periodic::new(2, 100)),
+            Verdict::Matched
+        );
+        assert_eq!(
+            machine(CLOCK).run_with(&mut Periodic::new(3, 100)),
+            Verdict::Mismatched(3)
+        );
+
+        assert_eq!(
+            machine(COUNTDOWN).run_with(&mut Prefix::new(vec![3, 2])),
+            Verdict::Matched
+        );
+        assert_eq!(
+            machine(COUNTDOWN).run_with(&mut Prefix::new(vec![3, 1])),
+            Verdict::Mismatched(1)
+        );
+        // Halting before the whole prefix is out leaves it undecided
+        assert_eq!(
+            machine(COUNTDOWN).run_with(&mut Prefix::new(vec![3, 2, 1, 0, 0])),
+            Verdict::Undecided
+        );
+    }
+}
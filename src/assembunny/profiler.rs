@@ -0,0 +1,162 @@
+use std::ops::RangeInclusive;
+
+use super::{Instruction, Machine, Operand};
+
+// Share of all cycles a loop needs to be called hot
+const HOT_SHARE: f64 = 0.1;
+
+pub struct Profile {
+    // The program as it was at the end of the run, toggles included
+    pub program: Vec<Instruction>,
+    pub hits: Vec<usize>,
+    pub jumps_taken: Vec<usize>,
+    pub cycles: usize,
+}
+
+// Runs the machine until it halts, waits for input or 'max_cycles' steps have been taken,
+// counting what executes. An optimized machine counts each fused block as a single hit at its
+// first instruction.
+pub fn profile(machine: &mut Machine, max_cycles: usize) -> Profile {
+    let mut hits = vec![0; machine.program.len()];
+    let mut jumps_taken = vec![0; machine.program.len()];
+    let mut cycles = 0;
+
+    while cycles < max_cycles && !machine.is_halted() && !machine.is_waiting() {
+        let pc = machine.pc as usize;
+        let taken = match machine.program[pc] {
+            Instruction::Jnz(x, _) => machine.value(x) != 0,
+            _ => false,
+        };
+
+        // An overflowing instruction never completes
+        if !machine.step() {
+            break;
+        }
+        hits[pc] += 1;
+        jumps_taken[pc] += taken as usize;
+        cycles += 1;
+    }
+
+    Profile {
+        program: machine.program.clone(),
+        hits,
+        jumps_taken,
+        cycles,
+    }
+}
+
+impl Profile {
+    // Backward jumps with a constant offset that were taken at least once, as the range of
+    // instructions they repeat.
+    pub fn loops(&self) -> Vec<RangeInclusive<usize>> {
+        self.program
+            .iter()
+            .enumerate()
+            .filter_map(|(pc, instruction)| match instruction {
+                Instruction::Jnz(_, Operand::Value(offset))
+                    if *offset <= 0 && self.jumps_taken[pc] > 0 =>
+                {
+                    let start = pc as i32 + offset;
+                    (start >= 0).then_some(start as usize..=pc)
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn hot_loops(&self) -> Vec<RangeInclusive<usize>> {
+        self.loops()
+            .into_iter()
+            .filter(|range| {
+                let loop_hits: usize = self.hits[range.clone()].iter().sum();
+                loop_hits as f64 >= HOT_SHARE * self.cycles as f64
+            })
+            .collect()
+    }
+
+    // The program with hit counts, share of cycles and taken jumps per instruction. Instructions
+    // inside hot loops are marked with '#'.
+    pub fn listing(&self) -> String {
+        let hot_loops = self.hot_loops();
+
+        let mut listing = format!("{} cycles\n", self.cycles);
+        for (pc, instruction) in self.program.iter().enumerate() {
+            let marker = if hot_loops.iter().any(|range| range.contains(&pc)) {
+                '#'
+            } else {
+                ' '
+            };
+            let share = 100.0 * self.hits[pc] as f64 / self.cycles.max(1) as f64;
+
+            listing += &format!(
+                "{} {:>3} {:>12} {:>6.2}%  {}",
+                marker, pc, self.hits[pc], share, instruction
+            );
+            if let Instruction::Jnz(..) = instruction {
+                listing += &format!("  (taken {})", self.jumps_taken[pc]);
+            }
+            listing += "\n";
+        }
+
+        listing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembunny::parse;
+
+    static TEST_INPUT: &str = "cpy 100 c
+inc a
+dec c
+jnz c -2
+inc b
+jnz b 2
+inc d";
+
+    #[test]
+    fn test_profile() {
+        let mut machine = Machine::new(parse(TEST_INPUT), [0; 4]);
+        let profile = profile(&mut machine, usize::MAX);
+
+        assert_eq!(profile.cycles, 303);
+        assert_eq!(profile.hits, vec![1, 100, 100, 100, 1, 1, 0]);
+        assert_eq!(profile.jumps_taken, vec![0, 0, 0, 99, 0, 1, 0]);
+        assert_eq!(profile.loops(), vec![1..=3]);
+        assert_eq!(profile.hot_loops(), vec![1..=3]);
+    }
+
+    #[test]
+    fn test_max_cycles() {
+        let mut machine = Machine::new(parse("inc a\njnz 1 -1"), [0; 4]);
+        let profile = profile(&mut machine, 1_000);
+
+        assert_eq!(profile.cycles, 1_000);
+        assert_eq!(profile.hits, vec![500, 500]);
+        assert_eq!(machine.registers[0], 500);
+    }
+
+    #[test]
+    fn test_stops_when_waiting() {
+        let mut machine = Machine::new(parse("inc b\nin a\ninc c"), [0; 4]);
+        let profile = profile(&mut machine, usize::MAX);
+
+        assert_eq!(profile.cycles, 1);
+        assert_eq!(profile.hits, vec![1, 0, 0]);
+        assert_eq!(machine.cycles, 1);
+        assert!(machine.is_waiting());
+    }
+
+    #[test]
+    fn test_listing() {
+        let mut machine = Machine::new(parse(TEST_INPUT), [0; 4]);
+        let listing = profile(&mut machine, usize::MAX).listing();
+        let lines: Vec<&str> = listing.lines().collect();
+
+        assert_eq!(lines[0], "303 cycles");
+        assert_eq!(lines[1], "    0            1   0.33%  cpy 100 c");
+        assert_eq!(lines[4], "#   3          100  33.00%  jnz c -2  (taken 99)");
+        assert_eq!(lines[6], "    5            1   0.33%  jnz b 2  (taken 1)");
+    }
+}
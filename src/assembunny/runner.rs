@@ -0,0 +1,366 @@
+// Runs any program with the registers, strategy and cycle cap given on the command line, for the
+// 'assembunny' binary:
+//
+//     assembunny run program.txt --reg a=12 --reg c=1 --strategy threaded --max-cycles 1000000
+//
+// 'debug' and 'profile' take the same options, but only the interpreted and optimized strategies.
+// 'profile' defaults to interpreted, so that fused loops do not hide where the cycles go.
+
+use std::fmt;
+use std::str::FromStr;
+
+use super::input::Source;
+use super::threaded::Threaded;
+use super::{register_name, Instruction, Machine, Operand};
+
+pub const USAGE: &str = "Usage: assembunny run|debug|profile <file> [--reg <register>=<value>]... \
+[--strategy interpreted|optimized|threaded] [--max-cycles <count>]";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Strategy {
+    Interpreted,
+    Optimized,
+    // Registers are only i32 here, without overflow checks
+    Threaded,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "interpreted" => Ok(Strategy::Interpreted),
+            "optimized" => Ok(Strategy::Optimized),
+            "threaded" => Ok(Strategy::Threaded),
+            _ => Err(format!("Unknown strategy: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Options {
+    pub path: String,
+    pub registers: [i64; 4],
+    pub strategy: Strategy,
+    pub max_cycles: usize,
+}
+
+fn register(s: &str) -> Result<(usize, i64), String> {
+    let error = || format!("Expected <register>=<value>, got: {}", s);
+
+    let (name, value) = s.split_once('=').ok_or_else(error)?;
+    let Ok(Operand::Register(r)) = name.parse() else {
+        return Err(format!("Unknown register: {}", name));
+    };
+    Ok((r, value.parse().map_err(|_| error())?))
+}
+
+impl Options {
+    // The arguments after the subcommand, with the strategy used if none is given
+    pub fn parse(args: &[String], strategy: Strategy) -> Result<Self, String> {
+        let mut path = None;
+        let mut options = Options {
+            path: String::new(),
+            registers: [0; 4],
+            strategy,
+            max_cycles: usize::MAX,
+        };
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| format!("Missing value for {}", arg))
+            };
+
+            match arg.as_str() {
+                "--reg" => {
+                    let (r, value) = register(value()?)?;
+                    options.registers[r] = value;
+                }
+                "--strategy" => options.strategy = value()?.parse()?,
+                "--max-cycles" => {
+                    let value = value()?;
+                    options.max_cycles = value
+                        .parse()
+                        .map_err(|_| format!("Unknown cycle count: {}", value))?;
+                }
+                _ if arg.starts_with("--") => return Err(format!("Unknown option: {}", arg)),
+                _ if path.is_none() => path = Some(arg.clone()),
+                _ => return Err(format!("Unexpected argument: {}", arg)),
+            }
+        }
+
+        options.path = path.ok_or("Missing program file")?;
+        Ok(options)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Status {
+    Halted,
+    CycleLimit,
+    // An 'in' after the input ran out
+    WaitingForInput,
+    Overflow(usize, Instruction),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    pub registers: [i64; 4],
+    pub output: Vec<i64>,
+    pub cycles: usize,
+    pub status: Status,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.status {
+            Status::Halted => writeln!(f, "halted")?,
+            Status::CycleLimit => writeln!(f, "stopped at the cycle limit")?,
+            Status::WaitingForInput => writeln!(f, "stopped waiting for input")?,
+            Status::Overflow(pc, instruction) => {
+                writeln!(f, "overflowed at {}: {}", pc, instruction)?
+            }
+        }
+
+        let registers: Vec<String> = self
+            .registers
+            .iter()
+            .enumerate()
+            .map(|(r, value)| format!("{}={}", register_name(r), value))
+            .collect();
+        writeln!(f, "registers: {}", registers.join(" "))?;
+
+        let output: Vec<String> = self.output.iter().map(|v| v.to_string()).collect();
+        writeln!(f, "output: {}", output.join(" "))?;
+        write!(f, "cycles: {}", self.cycles)
+    }
+}
+
+fn run_machine(mut machine: Machine<i64>, max_cycles: usize, source: &mut impl Source) -> Report {
+    let status = loop {
+        if machine.cycles >= max_cycles {
+            break Status::CycleLimit;
+        }
+        if machine.step() {
+            continue;
+        }
+        if let Some((pc, instruction)) = machine.overflow {
+            break Status::Overflow(pc, instruction);
+        }
+        if !machine.is_waiting() {
+            break Status::Halted;
+        }
+        match source.read() {
+            Some(value) => machine.input.push_back(value as i64),
+            None => break Status::WaitingForInput,
+        }
+    };
+
+    Report {
+        registers: machine.registers,
+        output: machine.output,
+        cycles: machine.cycles,
+        status,
+    }
+}
+
+fn run_threaded(mut threaded: Threaded, max_cycles: usize, source: &mut impl Source) -> Report {
+    let status = loop {
+        threaded.run_for(max_cycles);
+        if threaded.is_halted() {
+            break Status::Halted;
+        }
+        if !threaded.is_waiting() {
+            break Status::CycleLimit;
+        }
+        match source.read() {
+            Some(value) => threaded.state.input.push_back(value),
+            None => break Status::WaitingForInput,
+        }
+    };
+
+    Report {
+        registers: threaded.state.registers.map(i64::from),
+        output: threaded.state.output.iter().map(|&v| v as i64).collect(),
+        cycles: threaded.cycles,
+        status,
+    }
+}
+
+// The registers as i32s, for the machines without overflow checks
+fn small_registers(options: &Options, purpose: &str) -> Result<[i32; 4], String> {
+    let mut registers = [0; 4];
+    for (r, &value) in options.registers.iter().enumerate() {
+        registers[r] = i32::try_from(value)
+            .map_err(|_| format!("{} is too large for {}", register_name(r), purpose))?;
+    }
+    Ok(registers)
+}
+
+// A machine set up as the options say, for the debugger and profiler
+pub fn machine(
+    program: Vec<Instruction>,
+    options: &Options,
+    purpose: &str,
+) -> Result<Machine, String> {
+    let mut machine = Machine::new(program, small_registers(options, purpose)?);
+    match options.strategy {
+        Strategy::Interpreted => {}
+        Strategy::Optimized => machine.optimize(),
+        Strategy::Threaded => {
+            return Err(format!(
+                "The threaded strategy cannot be used for {}",
+                purpose
+            ))
+        }
+    }
+    Ok(machine)
+}
+
+// Runs the program as the options say. 'in' reads from 'source'.
+pub fn run(
+    program: Vec<Instruction>,
+    options: &Options,
+    source: &mut impl Source,
+) -> Result<Report, String> {
+    Ok(match options.strategy {
+        Strategy::Interpreted => run_machine(
+            Machine::new(program, options.registers),
+            options.max_cycles,
+            source,
+        ),
+        Strategy::Optimized => {
+            let mut machine = Machine::new(program, options.registers);
+            machine.optimize();
+            run_machine(machine, options.max_cycles, source)
+        }
+        Strategy::Threaded => run_threaded(
+            Threaded::new(program, small_registers(options, "the threaded strategy")?),
+            options.max_cycles,
+            source,
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+    use crate::assembunny::parse;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_parse_options() {
+        assert_eq!(
+            Options::parse(
+                &args("day23.txt --reg a=12 --reg c=-1 --strategy threaded --max-cycles 1000"),
+                Strategy::Optimized
+            ),
+            Ok(Options {
+                path: "day23.txt".to_string(),
+                registers: [12, 0, -1, 0],
+                strategy: Strategy::Threaded,
+                max_cycles: 1000,
+            })
+        );
+
+        let options = Options::parse(&args("x"), Strategy::Interpreted).unwrap();
+        assert_eq!(options.strategy, Strategy::Interpreted);
+
+        let error = |line| Options::parse(&args(line), Strategy::Optimized).unwrap_err();
+        assert_eq!(error("--reg a=1"), "Missing program file");
+        assert_eq!(error("x --reg e=1"), "Unknown register: e");
+        assert_eq!(error("x --reg a"), "Expected <register>=<value>, got: a");
+        assert_eq!(error("x --reg"), "Missing value for --reg");
+        assert_eq!(error("x --strategy jit"), "Unknown strategy: jit");
+        assert_eq!(error("x --verbose"), "Unknown option: --verbose");
+        assert_eq!(error("x y"), "Unexpected argument: y");
+    }
+
+    #[test]
+    fn test_strategies_agree() {
+        // Day 12's example followed by a countdown of c
+        let program =
+            parse("cpy 41 a\ninc a\ninc a\ndec a\njnz a 2\ndec a\nout c\ndec c\njnz c -2");
+
+        let mut options = Options::parse(&args("x --reg c=3"), Strategy::Optimized).unwrap();
+        let mut reports = Vec::new();
+        for strategy in [
+            Strategy::Interpreted,
+            Strategy::Optimized,
+            Strategy::Threaded,
+        ] {
+            options.strategy = strategy;
+            reports.push(run(program.clone(), &options, &mut VecDeque::new()).unwrap());
+        }
+
+        assert_eq!(
+            reports[0].to_string(),
+            "halted\nregisters: a=42 b=0 c=0 d=0\noutput: 3 2 1\ncycles: 14"
+        );
+        assert!(reports.iter().all(|r| *r == reports[0]));
+    }
+
+    #[test]
+    fn test_stops() {
+        let clock = parse("out a\ninc a\njnz 1 -2");
+        let options = Options::parse(&args("x --max-cycles 7"), Strategy::Optimized).unwrap();
+        let report = run(clock, &options, &mut VecDeque::new()).unwrap();
+        assert_eq!(report.status, Status::CycleLimit);
+        assert_eq!((report.output, report.cycles), (vec![0, 1, 2], 7));
+
+        let options = Options::parse(&args("x"), Strategy::Optimized).unwrap();
+        let report = run(parse("in a\nin b"), &options, &mut VecDeque::from([4])).unwrap();
+        assert_eq!(report.status, Status::WaitingForInput);
+        assert_eq!(report.registers, [4, 0, 0, 0]);
+
+        let options =
+            Options::parse(&args("x --reg a=9223372036854775807"), Strategy::Optimized).unwrap();
+        let report = run(parse("inc a"), &options, &mut VecDeque::new()).unwrap();
+        assert_eq!(report.status, Status::Overflow(0, parse("inc a")[0]));
+
+        let options = Options::parse(
+            &args("x --reg b=3000000000 --strategy threaded"),
+            Strategy::Optimized,
+        )
+        .unwrap();
+        assert_eq!(
+            run(parse("inc a"), &options, &mut VecDeque::new()),
+            Err("b is too large for the threaded strategy".to_string())
+        );
+    }
+
+    #[test]
+    fn test_machine() {
+        let program = parse("cpy 2 a\ninc b\ndec a\njnz a -2");
+
+        let options = Options::parse(&args("x --reg c=5"), Strategy::Optimized).unwrap();
+        let optimized = machine(program.clone(), &options, "the debugger").unwrap();
+        assert_eq!(optimized.registers, [0, 0, 5, 0]);
+        assert!(!optimized.blocks.is_empty());
+
+        let options =
+            Options::parse(&args("x --strategy interpreted"), Strategy::Optimized).unwrap();
+        assert!(machine(program.clone(), &options, "the debugger")
+            .unwrap()
+            .blocks
+            .is_empty());
+
+        let options = Options::parse(&args("x --strategy threaded"), Strategy::Optimized).unwrap();
+        assert_eq!(
+            machine(program.clone(), &options, "the profiler").err(),
+            Some("The threaded strategy cannot be used for the profiler".to_string())
+        );
+        let options = Options::parse(&args("x --reg d=-3000000000"), Strategy::Optimized).unwrap();
+        assert_eq!(
+            machine(program, &options, "the profiler").err(),
+            Some("d is too large for the profiler".to_string())
+        );
+    }
+}
@@ -0,0 +1,337 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::{Add, Mul, Sub};
+
+use super::optimizer::{self, Block};
+use super::{register_name, Instruction, Operand};
+
+// Programs that take longer than this are assumed to loop on something that has no closed form
+const MAX_STEPS: usize = 1_000_000;
+
+// A polynomial over the values the registers had when the symbolic run started. Each term is keyed
+// by the powers of a, b, c and d in it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expr(BTreeMap<[u32; 4], i64>);
+
+impl Expr {
+    pub fn constant(value: i64) -> Self {
+        let mut terms = BTreeMap::new();
+        if value != 0 {
+            terms.insert([0; 4], value);
+        }
+        Expr(terms)
+    }
+
+    // The starting value of register 'r'
+    pub fn register(r: usize) -> Self {
+        let mut powers = [0; 4];
+        powers[r] = 1;
+        Expr(BTreeMap::from([(powers, 1)]))
+    }
+
+    pub fn as_constant(&self) -> Option<i64> {
+        match self.0.iter().next() {
+            None => Some(0),
+            Some((&powers, &value)) if self.0.len() == 1 && powers == [0; 4] => Some(value),
+            _ => None,
+        }
+    }
+
+    fn from_operand(operand: Operand, registers: &[Expr; 4]) -> Self {
+        match operand {
+            Operand::Register(r) => registers[r].clone(),
+            Operand::Value(v) => Expr::constant(v as i64),
+        }
+    }
+}
+
+impl Add for Expr {
+    type Output = Expr;
+
+    fn add(mut self, other: Expr) -> Expr {
+        for (powers, value) in other.0 {
+            let sum = self.0.get(&powers).unwrap_or(&0) + value;
+            if sum == 0 {
+                self.0.remove(&powers);
+            } else {
+                self.0.insert(powers, sum);
+            }
+        }
+        self
+    }
+}
+
+impl Sub for Expr {
+    type Output = Expr;
+
+    fn sub(self, other: Expr) -> Expr {
+        self + Expr::constant(-1) * other
+    }
+}
+
+impl Mul for Expr {
+    type Output = Expr;
+
+    fn mul(self, other: Expr) -> Expr {
+        let mut product = Expr::constant(0);
+        for (x_powers, x_value) in &self.0 {
+            for (y_powers, y_value) in &other.0 {
+                let powers = [0, 1, 2, 3].map(|r| x_powers[r] + y_powers[r]);
+                product = product + Expr(BTreeMap::from([(powers, x_value * y_value)]));
+            }
+        }
+        product
+    }
+}
+
+// Highest powers first, e.g. "a^2*b - 3*a + 2"
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "0");
+        }
+
+        let mut terms: Vec<_> = self.0.iter().collect();
+        terms.sort_by_key(|(powers, _)| (powers.iter().sum::<u32>(), **powers));
+
+        for (i, (powers, value)) in terms.into_iter().rev().enumerate() {
+            match (i, *value < 0) {
+                (0, true) => write!(f, "-")?,
+                (0, false) => {}
+                (_, true) => write!(f, " - ")?,
+                (_, false) => write!(f, " + ")?,
+            }
+
+            let mut factors: Vec<String> = (0..4)
+                .filter(|&r| powers[r] > 0)
+                .map(|r| match powers[r] {
+                    1 => register_name(r).to_string(),
+                    n => format!("{}^{}", register_name(r), n),
+                })
+                .collect();
+            if value.abs() != 1 || factors.is_empty() {
+                factors.insert(0, value.abs().to_string());
+            }
+            write!(f, "{}", factors.join("*"))?;
+        }
+
+        Ok(())
+    }
+}
+
+pub struct State {
+    pub pc: usize,
+    pub registers: [Expr; 4],
+    pub output: Vec<Expr>,
+    // The program as it was when the run stopped, toggles included
+    pub program: Vec<Instruction>,
+}
+
+// Number of loop iterations until 'value' stepped by 'step' reaches zero. Loops over a symbolic
+// counter are assumed to count down normally, as they do for the inputs the puzzles give.
+fn iterations(value: Expr, step: i32) -> Option<Expr> {
+    let n = value * Expr::constant(-step as i64);
+    match n.as_constant() {
+        Some(n) if n <= 0 => None,
+        _ => Some(n),
+    }
+}
+
+// Applies a fused loop to the registers, or returns false if it has to be interpreted instead.
+fn execute_block(block: Block, registers: &mut [Expr; 4]) -> bool {
+    match block {
+        Block::Add {
+            target,
+            target_step,
+            counter,
+            counter_step,
+        } => {
+            let Some(n) = iterations(registers[counter].clone(), counter_step) else {
+                return false;
+            };
+            registers[target] = registers[target].clone() + Expr::constant(target_step as i64) * n;
+            registers[counter] = Expr::constant(0);
+        }
+        Block::Mul {
+            source,
+            target,
+            target_step,
+            inner,
+            inner_step,
+            outer,
+            outer_step,
+        } => {
+            let source = Expr::from_operand(source, registers);
+            let (Some(n_inner), Some(n_outer)) = (
+                iterations(source, inner_step),
+                iterations(registers[outer].clone(), outer_step),
+            ) else {
+                return false;
+            };
+            registers[target] =
+                registers[target].clone() + Expr::constant(target_step as i64) * n_inner * n_outer;
+            registers[inner] = Expr::constant(0);
+            registers[outer] = Expr::constant(0);
+        }
+    }
+
+    true
+}
+
+// Runs the program from 'start' until the pc reaches 'stop', with the registers as expressions of
+// their values at 'start'. Halting counts as reaching 'program.len()'. Fails if a jump or a
+// toggle depends on a value that is not constant, as the run could then go more than one way.
+pub fn execute(
+    program: &[Instruction],
+    start: usize,
+    registers: [Expr; 4],
+    stop: usize,
+) -> Result<State, String> {
+    let mut program = program.to_vec();
+    let mut blocks = optimizer::find_blocks(&program);
+    let mut registers = registers;
+    let mut output = Vec::new();
+    let mut pc = start;
+
+    for _ in 0..MAX_STEPS {
+        if pc == stop {
+            return Ok(State {
+                pc,
+                registers,
+                output,
+                program,
+            });
+        }
+        if pc >= program.len() {
+            return Err(format!("Halted before reaching {}", stop));
+        }
+
+        if let Some(block) = blocks[pc] {
+            if execute_block(block, &mut registers) {
+                pc += block.len();
+                continue;
+            }
+        }
+
+        let constant = |operand, registers: &[Expr; 4]| {
+            let value = Expr::from_operand(operand, registers);
+            value
+                .as_constant()
+                .ok_or_else(|| format!("Depends on {} at {}", value, pc))
+        };
+
+        let mut next = pc as i64 + 1;
+        match program[pc] {
+            Instruction::Cpy(x, Operand::Register(r)) => {
+                registers[r] = Expr::from_operand(x, &registers)
+            }
+            Instruction::Inc(Operand::Register(r)) => {
+                registers[r] = registers[r].clone() + Expr::constant(1)
+            }
+            Instruction::Dec(Operand::Register(r)) => {
+                registers[r] = registers[r].clone() - Expr::constant(1)
+            }
+            Instruction::Jnz(x, y) => {
+                let jumps = constant(x, &registers)? != 0;
+                if jumps {
+                    next = pc as i64 + constant(y, &registers)?;
+                }
+            }
+            Instruction::Tgl(x) => {
+                let target = pc as i64 + constant(x, &registers)?;
+                if (0..program.len() as i64).contains(&target) {
+                    let target = target as usize;
+                    program[target] = program[target].toggled();
+                    optimizer::update_blocks(&mut blocks, &program, target);
+                }
+            }
+            Instruction::Out(x) => output.push(Expr::from_operand(x, &registers)),
+            Instruction::In(Operand::Register(_)) => return Err(format!("Reads input at {}", pc)),
+            Instruction::Custom(_) => {
+                return Err(format!("Unknown opcode at {}: {}", pc, program[pc]))
+            }
+            // Writes into a literal are skipped
+            _ => {}
+        }
+
+        pc = if (0..program.len() as i64).contains(&next) {
+            next as usize
+        } else {
+            program.len()
+        };
+    }
+
+    Err(format!("No closed form within {} steps", MAX_STEPS))
+}
+
+// Every register starting as its own symbol
+pub fn symbols() -> [Expr; 4] {
+    [0, 1, 2, 3].map(Expr::register)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembunny::parse;
+
+    #[test]
+    fn test_expr() {
+        let a = Expr::register(0);
+        let b = Expr::register(1);
+        let expr =
+            a.clone() * a.clone() * b.clone() - Expr::constant(3) * a.clone() + Expr::constant(2);
+
+        assert_eq!(expr.to_string(), "a^2*b - 3*a + 2");
+        assert_eq!((Expr::constant(0) - b.clone()).to_string(), "-b");
+        assert_eq!((a.clone() - a.clone()).to_string(), "0");
+        assert_eq!((expr.clone() - expr).as_constant(), Some(0));
+        assert_eq!((a + b).as_constant(), None);
+    }
+
+    #[test]
+    fn test_execute_loops() {
+        // b = (a + 2) * c, with the multiplication and the copy loop both fused
+        let program = parse(
+            "cpy a d
+inc d
+inc d
+cpy 0 b
+cpy d a
+inc b
+dec a
+jnz a -2
+dec c
+jnz c -5",
+        );
+
+        let state = execute(&program, 0, symbols(), program.len()).unwrap();
+        assert_eq!(state.registers[1].to_string(), "a*c + 2*c");
+        assert_eq!(state.registers[2].to_string(), "0");
+        assert_eq!(state.registers[3].to_string(), "a + 2");
+    }
+
+    #[test]
+    fn test_execute_to_pc() {
+        let program = parse("cpy a b\ninc b\nout b\njnz b 2\ndec a");
+
+        let state = execute(&program, 0, symbols(), 3).unwrap();
+        assert_eq!(state.pc, 3);
+        assert_eq!(state.registers[1].to_string(), "a + 1");
+        assert_eq!(state.output, vec![Expr::register(0) + Expr::constant(1)]);
+
+        assert_eq!(
+            execute(&program, 0, symbols(), 5).err(),
+            Some("Depends on a + 1 at 3".to_string())
+        );
+    }
+
+    #[test]
+    fn test_execute_tgl() {
+        let program = parse("cpy 2 c\ntgl c\ncpy a b\njnz 4 a");
+
+        let state = execute(&program, 0, symbols(), program.len()).unwrap();
+        assert_eq!(state.program[3].to_string(), "cpy 4 a");
+        assert_eq!(state.registers[0].to_string(), "4");
+        assert_eq!(state.registers[1].to_string(), "a");
+    }
+}
@@ -0,0 +1,332 @@
+// An execution backend without a central 'match': every instruction is turned into a closure
+// with its operands already resolved, and running the program is just calling them in turn.
+// Registers are plain i32 here, wrapping around instead of checking for overflow.
+
+use std::collections::VecDeque;
+
+use super::opcodes::{Cpu, Flow};
+use super::{Instruction, Operand};
+
+// Left by an instruction for the runner to act on
+enum Signal {
+    // Relative to the pc of the 'tgl'
+    Toggle(i32),
+    // An 'in' found the input empty
+    Wait,
+}
+
+pub struct State {
+    pub registers: [i32; 4],
+    pub output: Vec<i32>,
+    pub input: VecDeque<i32>,
+    signal: Option<Signal>,
+}
+
+// Executes one instruction and returns the offset to the next one
+type Op = Box<dyn Fn(&mut State) -> i32>;
+
+fn thread(instruction: Instruction) -> Op {
+    use Operand::{Register, Value};
+
+    match instruction {
+        Instruction::Cpy(Value(v), Register(y)) => Box::new(move |s| {
+            s.registers[y] = v;
+            1
+        }),
+        Instruction::Cpy(Register(x), Register(y)) => Box::new(move |s| {
+            s.registers[y] = s.registers[x];
+            1
+        }),
+        Instruction::Inc(Register(x)) => Box::new(move |s| {
+            s.registers[x] = s.registers[x].wrapping_add(1);
+            1
+        }),
+        Instruction::Dec(Register(x)) => Box::new(move |s| {
+            s.registers[x] = s.registers[x].wrapping_sub(1);
+            1
+        }),
+        Instruction::Jnz(Value(x), Value(y)) => {
+            let offset = if x != 0 { y } else { 1 };
+            Box::new(move |_| offset)
+        }
+        Instruction::Jnz(Register(x), Value(y)) => {
+            Box::new(move |s| if s.registers[x] != 0 { y } else { 1 })
+        }
+        Instruction::Jnz(Value(x), Register(y)) => {
+            if x != 0 {
+                Box::new(move |s| s.registers[y])
+            } else {
+                Box::new(|_| 1)
+            }
+        }
+        Instruction::Jnz(Register(x), Register(y)) => Box::new(move |s| {
+            if s.registers[x] != 0 {
+                s.registers[y]
+            } else {
+                1
+            }
+        }),
+        Instruction::Tgl(Value(x)) => Box::new(move |s| {
+            s.signal = Some(Signal::Toggle(x));
+            1
+        }),
+        Instruction::Tgl(Register(x)) => Box::new(move |s| {
+            s.signal = Some(Signal::Toggle(s.registers[x]));
+            1
+        }),
+        Instruction::Out(Value(x)) => Box::new(move |s| {
+            s.output.push(x);
+            1
+        }),
+        Instruction::Out(Register(x)) => Box::new(move |s| {
+            s.output.push(s.registers[x]);
+            1
+        }),
+        Instruction::In(Register(x)) => Box::new(move |s| match s.input.pop_front() {
+            Some(value) => {
+                s.registers[x] = value;
+                1
+            }
+            None => {
+                s.signal = Some(Signal::Wait);
+                0
+            }
+        }),
+        // Writes into a literal, only ever made by 'tgl', are skipped
+        Instruction::Cpy(_, Value(_))
+        | Instruction::Inc(Value(_))
+        | Instruction::Dec(Value(_))
+        | Instruction::In(Value(_)) => Box::new(|_| 1),
+        // Registered opcodes run through their own hook. Registers wrap, so there is no overflow
+        // to stop at.
+        Instruction::Custom(custom) => {
+            Box::new(move |s| match custom.opcode.execute(custom.operands(), s) {
+                Flow::Next | Flow::Skip | Flow::Overflow => 1,
+                Flow::Jump(offset) => clamp(offset),
+                Flow::Wait => {
+                    s.signal = Some(Signal::Wait);
+                    0
+                }
+            })
+        }
+    }
+}
+
+// Offsets too large for an i32 leave the program either way
+fn clamp(offset: i64) -> i32 {
+    offset.clamp(i32::MIN as i64, i32::MAX as i64) as i32
+}
+
+impl Cpu for State {
+    fn get(&self, operand: Operand) -> Option<i64> {
+        Some(match operand {
+            Operand::Register(r) => self.registers[r] as i64,
+            Operand::Value(v) => v as i64,
+        })
+    }
+
+    fn set(&mut self, register: usize, value: i64) -> bool {
+        self.registers[register] = value as i32;
+        true
+    }
+
+    fn copy(&mut self, from: Operand, register: usize) {
+        self.registers[register] = self.get(from).unwrap() as i32;
+    }
+
+    fn add(&mut self, register: usize, step: i32) -> bool {
+        self.registers[register] = self.registers[register].wrapping_add(step);
+        true
+    }
+
+    fn output(&mut self, operand: Operand) {
+        let value = self.get(operand).unwrap() as i32;
+        self.output.push(value);
+    }
+
+    fn input(&mut self, register: usize) -> bool {
+        let Some(value) = self.input.pop_front() else {
+            return false;
+        };
+        self.registers[register] = value;
+        true
+    }
+
+    fn toggle(&mut self, offset: i64) {
+        self.signal = Some(Signal::Toggle(clamp(offset)));
+    }
+}
+
+pub struct Threaded {
+    pub program: Vec<Instruction>,
+    pub state: State,
+    pub pc: i32,
+    // Instructions executed so far, not counting an 'in' still waiting
+    pub cycles: usize,
+    ops: Vec<Op>,
+}
+
+impl Threaded {
+    pub fn new(program: Vec<Instruction>, registers: [i32; 4]) -> Self {
+        Threaded {
+            ops: program.iter().map(|&i| thread(i)).collect(),
+            program,
+            state: State {
+                registers,
+                output: Vec::new(),
+                input: VecDeque::new(),
+                signal: None,
+            },
+            pc: 0,
+            cycles: 0,
+        }
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.pc < 0 || self.pc >= self.ops.len() as i32
+    }
+
+    pub fn is_waiting(&self) -> bool {
+        !self.is_halted()
+            && self.state.input.is_empty()
+            && matches!(
+                self.program[self.pc as usize],
+                Instruction::In(Operand::Register(_))
+            )
+    }
+
+    pub fn run(&mut self) {
+        self.run_for(usize::MAX);
+    }
+
+    // Runs until the program halts, 'max_cycles' have been reached or an 'in' finds the input
+    // empty. The pc is then left at the 'in', so that running again after adding input carries on
+    // from there.
+    pub fn run_for(&mut self, max_cycles: usize) {
+        while !self.is_halted() && self.cycles < max_cycles {
+            let pc = self.pc;
+            let offset = (self.ops[pc as usize])(&mut self.state);
+            // Offsets too large for the pc leave the program either way
+            self.pc = pc.checked_add(offset).unwrap_or(-1);
+            self.cycles += 1;
+
+            match self.state.signal.take() {
+                None => {}
+                // Only the toggled instruction is threaded again
+                Some(Signal::Toggle(offset)) => {
+                    let target = pc.checked_add(offset).unwrap_or(-1);
+                    if target >= 0 && target < self.program.len() as i32 {
+                        let target = target as usize;
+                        self.program[target] = self.program[target].toggled();
+                        self.ops[target] = thread(self.program[target]);
+                    }
+                }
+                Some(Signal::Wait) => {
+                    self.cycles -= 1;
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::assembunny::{parse, Machine};
+
+    // a! + 3 * 4, with the tail toggled into shape on the way
+    static FACTORIAL: &str = include_str!("fixtures/factorial.txt");
+
+    #[test]
+    fn test_run() {
+        let mut threaded = Threaded::new(parse("cpy 3 b\ninc a\ndec b\njnz b -2\nout a"), [0; 4]);
+        threaded.run();
+        assert_eq!(threaded.state.registers, [3, 0, 0, 0]);
+        assert_eq!(threaded.state.output, vec![3]);
+    }
+
+    #[test]
+    fn test_matches_interpreter_with_tgl() {
+        let mut threaded = Threaded::new(parse(FACTORIAL), [7, 0, 0, 0]);
+        threaded.run();
+
+        let mut machine = Machine::new(parse(FACTORIAL), [7, 0, 0, 0]);
+        machine.run();
+
+        assert_eq!(threaded.state.registers[0], 5040 + 12);
+        assert_eq!(threaded.state.registers, machine.registers);
+        assert_eq!(threaded.program, machine.program);
+    }
+
+    #[test]
+    fn test_waits_for_input() {
+        let mut threaded = Threaded::new(parse("in a\nin b\nout b"), [0; 4]);
+        threaded.state.input.push_back(5);
+        threaded.run();
+        assert_eq!((threaded.pc, threaded.state.registers[0]), (1, 5));
+        assert!(threaded.is_waiting());
+        assert_eq!(threaded.cycles, 1);
+
+        threaded.state.input.push_back(7);
+        threaded.run();
+        assert_eq!(threaded.state.output, vec![7]);
+    }
+
+    #[test]
+    fn test_run_for() {
+        let mut threaded = Threaded::new(parse("cpy 3 b\ninc a\ndec b\njnz b -2"), [0; 4]);
+        threaded.run_for(4);
+        assert_eq!((threaded.pc, threaded.cycles), (1, 4));
+        assert!(!threaded.is_halted());
+
+        threaded.run();
+        assert_eq!(threaded.cycles, 10);
+        assert!(threaded.is_halted());
+    }
+
+    #[test]
+    fn test_offsets_past_i32() {
+        for input in ["cpy 2147483647 a\njnz 1 a", "cpy 2147483647 a\ntgl a"] {
+            let mut threaded = Threaded::new(parse(input), [0; 4]);
+            threaded.run();
+            assert!(threaded.is_halted(), "{}", input);
+
+            let mut machine = Machine::new(parse(input), [0; 4]);
+            machine.run();
+            assert_eq!(threaded.state.registers, machine.registers, "{}", input);
+            assert_eq!(threaded.cycles, machine.cycles, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_toggled_into_literal_is_skipped() {
+        let mut threaded = Threaded::new(parse("tgl 2\ninc a\njnz 7 2\ninc a"), [0; 4]);
+        threaded.run();
+        assert_eq!(threaded.state.registers, [2, 0, 0, 0]);
+    }
+
+    // A benchmark rather than a test, best run with
+    // cargo test --release bench_against_interpreter -- --ignored --nocapture
+    #[ignore]
+    #[test]
+    fn bench_against_interpreter() {
+        let start = Instant::now();
+        let mut machine = Machine::new(parse(FACTORIAL), [10, 0, 0, 0]);
+        machine.run();
+        let interpreted = start.elapsed();
+
+        let start = Instant::now();
+        let mut threaded = Threaded::new(parse(FACTORIAL), [10, 0, 0, 0]);
+        threaded.run();
+        let threaded_time = start.elapsed();
+
+        assert_eq!(threaded.state.registers, machine.registers);
+        println!(
+            "interpreted: {:?}, threaded: {:?}",
+            interpreted, threaded_time
+        );
+    }
+}
@@ -0,0 +1,62 @@
+use std::fmt;
+use std::hash::Hash;
+
+// A value the registers can hold. Arithmetic is checked, so an overflow stops the machine instead
+// of wrapping around silently.
+pub trait Word: Clone + PartialEq + Eq + Hash + fmt::Debug + fmt::Display {
+    fn from_i32(value: i32) -> Self;
+    // For registered opcodes, which compute in i64 whatever the register width
+    fn from_i64(value: i64) -> Option<Self>;
+    fn to_i64(&self) -> Option<i64>;
+    // For jump and toggle offsets, which have to fit the program counter
+    fn to_i32(&self) -> Option<i32>;
+    // For the number of instructions a fused loop stands for
+    fn to_usize(&self) -> Option<usize>;
+    fn checked_add(&self, other: &Self) -> Option<Self>;
+    fn checked_mul(&self, other: &Self) -> Option<Self>;
+    fn is_positive(&self) -> bool;
+
+    fn is_zero(&self) -> bool {
+        *self == Self::from_i32(0)
+    }
+}
+
+macro_rules! fixed_width_word {
+    ($($t:ty), *) => ($(
+        impl Word for $t {
+            fn from_i32(value: i32) -> Self {
+                value.into()
+            }
+
+            fn from_i64(value: i64) -> Option<Self> {
+                <$t>::try_from(value).ok()
+            }
+
+            fn to_i64(&self) -> Option<i64> {
+                i64::try_from(*self).ok()
+            }
+
+            fn to_i32(&self) -> Option<i32> {
+                i32::try_from(*self).ok()
+            }
+
+            fn to_usize(&self) -> Option<usize> {
+                usize::try_from(*self).ok()
+            }
+
+            fn checked_add(&self, other: &Self) -> Option<Self> {
+                <$t>::checked_add(*self, *other)
+            }
+
+            fn checked_mul(&self, other: &Self) -> Option<Self> {
+                <$t>::checked_mul(*self, *other)
+            }
+
+            fn is_positive(&self) -> bool {
+                *self > 0
+            }
+        }
+    )*);
+}
+
+fixed_width_word!(i32, i64);
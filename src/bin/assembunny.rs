@@ -0,0 +1,65 @@
+// Runs, debugs or profiles any Assembunny program outside of the puzzles. When run, 'in' reads a
+// number per line from stdin; the debugger reads commands from it instead.
+
+use std::io::{self, Write};
+use std::{env, fs, process};
+
+use aoc2016::assembunny::assembler::assemble;
+use aoc2016::assembunny::debugger::Debugger;
+use aoc2016::assembunny::input;
+use aoc2016::assembunny::profiler::profile;
+use aoc2016::assembunny::runner::{self, Options, Strategy, USAGE};
+
+fn run(args: &[String]) -> Result<(), String> {
+    let (command, options) = match args {
+        [command, rest @ ..] if ["run", "debug"].contains(&command.as_str()) => {
+            (command.as_str(), Options::parse(rest, Strategy::Optimized)?)
+        }
+        // Every instruction counted where it runs, unless optimizing is asked for
+        [command, rest @ ..] if command == "profile" => {
+            ("profile", Options::parse(rest, Strategy::Interpreted)?)
+        }
+        _ => return Err(USAGE.to_string()),
+    };
+
+    let source = fs::read_to_string(&options.path)
+        .map_err(|e| format!("Cannot read {}: {}", options.path, e))?;
+    let program = assemble(&source)?;
+
+    match command {
+        "debug" => {
+            let machine = runner::machine(program, &options, "the debugger")?;
+            let mut debugger = Debugger::new(machine);
+            debugger
+                .repl(io::stdin().lock(), io::stdout())
+                .map_err(|e| e.to_string())?;
+        }
+        "profile" => {
+            let mut machine = runner::machine(program, &options, "the profiler")?;
+            let profile = profile(&mut machine, options.max_cycles);
+            print!("{}", profile.listing());
+            if machine.is_waiting() {
+                println!("stopped waiting for input");
+            }
+            io::stdout().flush().map_err(|e| e.to_string())?;
+        }
+        _ => {
+            let mut stdin = input::stdin();
+            let report = runner::run(program, &options, &mut stdin)?;
+            if let Some(error) = stdin.error {
+                eprintln!("{}", error);
+            }
+            println!("{}", report);
+        }
+    }
+
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(e) = run(&args) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
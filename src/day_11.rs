@@ -1,4 +1,86 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::str::FromStr;
+
 use aoc_runner_derive::aoc;
+use itertools::Itertools;
+
+const FLOORS: usize = 4;
+const FLOOR_NAMES: [&str; FLOORS] = ["first", "second", "third", "fourth"];
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct Element(String);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Item {
+    Generator(Element),
+    Microchip(Element),
+}
+
+impl fmt::Display for Item {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Item::Generator(element) => write!(f, "{} generator", element.0),
+            Item::Microchip(element) => write!(f, "{} microchip", element.0),
+        }
+    }
+}
+
+impl FromStr for Item {
+    type Err = String;
+
+    // "a lithium generator" or "a hydrogen-compatible microchip"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+
+        match words[..] {
+            ["a" | "an", element, "generator"] => Ok(Item::Generator(Element(element.to_string()))),
+            ["a" | "an", element, "microchip"] => element
+                .strip_suffix("-compatible")
+                .map(|element| Item::Microchip(Element(element.to_string())))
+                .ok_or_else(|| format!("Unknown microchip: {}", s)),
+            _ => Err(format!("Unknown item: {}", s)),
+        }
+    }
+}
+
+fn parse_floor(line: &str, name: &str) -> Result<Vec<Item>, String> {
+    let contents = line
+        .strip_prefix(&format!("The {} floor contains ", name))
+        .ok_or_else(|| format!("Expected the {} floor: {}", name, line))?;
+    let contents = contents
+        .strip_suffix('.')
+        .ok_or_else(|| format!("Expected a full stop: {}", line))?;
+
+    if contents == "nothing relevant" {
+        return Ok(vec![]);
+    }
+
+    // "a, b, and c", "a, b and c" or "a and b"
+    contents
+        .split(", ")
+        .flat_map(|part| part.split(" and "))
+        .map(|part| part.strip_prefix("and ").unwrap_or(part))
+        .map(|item| item.parse())
+        .collect()
+}
+
+// The items on each floor, from the bottom up. Errors give the 1-based line they were found on.
+fn parse(input: &str) -> Result<Vec<Vec<Item>>, String> {
+    let lines: Vec<&str> = input.lines().filter(|l| !l.trim().is_empty()).collect();
+    if lines.len() != FLOORS {
+        return Err(format!("Expected {} floors, got {}", FLOORS, lines.len()));
+    }
+
+    lines
+        .iter()
+        .zip(FLOOR_NAMES)
+        .enumerate()
+        .map(|(idx, (line, name))| {
+            parse_floor(line.trim(), name).map_err(|e| format!("line {}: {}", idx + 1, e))
+        })
+        .collect()
+}
 
 fn optimal_moves(items: u32) -> u32 {
     // Optimal moves for moving n items up a floor
@@ -13,32 +95,413 @@ fn solve(items_per_floor: [u32; 3]) -> u32 {
         + optimal_moves(on_floor_1 + on_floor_2 + on_floor_3)
 }
 
-fn parse_starting_counts(input: &str) -> [u32; 3] {
-    let counts: Vec<u32> = input
+fn starting_counts(floors: &[Vec<Item>]) -> [u32; 3] {
+    [
+        floors[0].len() as u32,
+        floors[1].len() as u32,
+        floors[2].len() as u32,
+    ]
+}
+
+// The floors of the generator and the microchip of one element
+type Pair = (usize, usize);
+
+// Elements are interchangeable, so only which floors their pairs are on matters. Keeping the
+// pairs sorted makes states that differ only by naming the same.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct State {
+    elevator: usize,
+    pairs: Vec<Pair>,
+}
+
+impl State {
+    fn new(elevator: usize, mut pairs: Vec<Pair>) -> Self {
+        pairs.sort();
+        State { elevator, pairs }
+    }
+
+    // A microchip away from its generator is fried by any other generator on its floor
+    fn is_safe(&self) -> bool {
+        self.pairs.iter().all(|&(generator, microchip)| {
+            generator == microchip || self.pairs.iter().all(|&(other, _)| other != microchip)
+        })
+    }
+
+    fn is_done(&self) -> bool {
+        self.pairs
+            .iter()
+            .all(|&(generator, microchip)| generator == FLOORS - 1 && microchip == FLOORS - 1)
+    }
+
+    fn next_states(&self) -> Vec<State> {
+        // (pair, 0 for the generator or 1 for the microchip) of everything on the elevator's floor
+        let here: Vec<(usize, usize)> = self
+            .pairs
+            .iter()
+            .enumerate()
+            .flat_map(|(idx, &(generator, microchip))| [(idx, 0, generator), (idx, 1, microchip)])
+            .filter(|&(_, _, floor)| floor == self.elevator)
+            .map(|(idx, item, _)| (idx, item))
+            .collect();
+
+        // Nothing is ever needed again from below the lowest floor with items on it
+        let lowest = self
+            .pairs
+            .iter()
+            .map(|&(generator, microchip)| generator.min(microchip))
+            .min()
+            .unwrap_or(0);
+        let mut floors = Vec::new();
+        if self.elevator + 1 < FLOORS {
+            floors.push(self.elevator + 1);
+        }
+        if self.elevator > lowest {
+            floors.push(self.elevator - 1);
+        }
+
+        let carried = here.iter().map(|&item| vec![item]).chain(
+            here.iter()
+                .combinations(2)
+                .map(|items| vec![*items[0], *items[1]]),
+        );
+
+        carried
+            .cartesian_product(floors)
+            .map(|(items, floor)| {
+                let mut pairs = self.pairs.clone();
+                for (idx, item) in items {
+                    match item {
+                        0 => pairs[idx].0 = floor,
+                        _ => pairs[idx].1 = floor,
+                    }
+                }
+                State::new(floor, pairs)
+            })
+            .filter(|state| state.is_safe())
+            .collect()
+    }
+}
+
+// Breadth-first over the floor states, for the fewest elevator moves that get everything to the
+// top floor. Returns the states on the way, starting from the first one.
+fn shortest_path(pairs: Vec<Pair>) -> Option<Vec<State>> {
+    let start = State::new(0, pairs);
+    // Each state seen, with the one it was first reached from
+    let mut previous: HashMap<State, Option<State>> = HashMap::from([(start.clone(), None)]);
+    let mut queue = VecDeque::from([start]);
+
+    while let Some(state) = queue.pop_front() {
+        if state.is_done() {
+            let mut path = vec![state];
+            while let Some(Some(state)) = previous.get(path.last().unwrap()) {
+                path.push(state.clone());
+            }
+            path.reverse();
+            return Some(path);
+        }
+
+        for next in state.next_states() {
+            if !previous.contains_key(&next) {
+                previous.insert(next.clone(), Some(state.clone()));
+                queue.push_back(next);
+            }
+        }
+    }
+
+    None
+}
+
+fn min_steps(pairs: Vec<Pair>) -> Option<u32> {
+    shortest_path(pairs).map(|path| path.len() as u32 - 1)
+}
+
+fn pairs(floors: &[Vec<Item>]) -> Result<Vec<Pair>, String> {
+    let mut generators = BTreeMap::new();
+    let mut microchips = BTreeMap::new();
+    for (floor, items) in floors.iter().enumerate() {
+        for item in items {
+            let (found, element, kind) = match item {
+                Item::Generator(element) => (&mut generators, element, "generator"),
+                Item::Microchip(element) => (&mut microchips, element, "microchip"),
+            };
+            if found.insert(element, floor).is_some() {
+                return Err(format!("More than one {} {}", element.0, kind));
+            }
+        }
+    }
+
+    if let Some(element) = generators.keys().find(|e| !microchips.contains_key(*e)) {
+        return Err(format!("No microchip for the {} generator", element.0));
+    }
+    if let Some(element) = microchips.keys().find(|e| !generators.contains_key(*e)) {
+        return Err(format!("No generator for the {} microchip", element.0));
+    }
+
+    Ok(generators
+        .iter()
+        .map(|(element, &floor)| (floor, microchips[element]))
+        .collect())
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Direction {
+    Up,
+    Down,
+}
+
+// One ride of the elevator
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Move {
+    direction: Direction,
+    items: Vec<Item>,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::Up => write!(f, "up"),
+            Direction::Down => write!(f, "down"),
+        }
+    }
+}
+
+impl fmt::Display for Move {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.direction, self.items.iter().join(", "))
+    }
+}
+
+impl FromStr for Move {
+    type Err = String;
+
+    // "up: hydrogen microchip, lithium generator"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (direction, items) = s.split_once(':').unwrap_or((s, ""));
+        let direction = match direction.trim() {
+            "up" => Direction::Up,
+            "down" => Direction::Down,
+            _ => return Err(format!("Unknown direction: {}", direction.trim())),
+        };
+
+        let items = items
+            .split(',')
+            .filter(|item| !item.trim().is_empty())
+            .map(|item| {
+                let words: Vec<&str> = item.split_whitespace().collect();
+                match words[..] {
+                    [element, "generator"] => Ok(Item::Generator(Element(element.to_string()))),
+                    [element, "microchip"] => Ok(Item::Microchip(Element(element.to_string()))),
+                    _ => Err(format!("Unknown item: {}", item.trim())),
+                }
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Move { direction, items })
+    }
+}
+
+// Plans are a move per line
+fn parse_plan(input: &str) -> Result<Vec<Move>, String> {
+    input
         .lines()
-        .take(3)
-        .map(|l| l.chars().filter(|&c| c == ',').count() as u32)
-        .map(|v| v + 1)
-        .collect();
+        .filter(|l| !l.trim().is_empty())
+        .enumerate()
+        .map(|(idx, line)| line.parse().map_err(|e| format!("step {}: {}", idx + 1, e)))
+        .collect()
+}
 
-    [counts[0], counts[1], counts[2]]
+// The floors with the actual items, for following a plan
+#[derive(Debug, Clone)]
+struct Building {
+    elevator: usize,
+    floors: Vec<Vec<Item>>,
+}
+
+impl Building {
+    fn new(floors: Vec<Vec<Item>>) -> Self {
+        Building {
+            elevator: 0,
+            floors,
+        }
+    }
+
+    // Makes the move, or says why it cannot be made
+    fn apply(&mut self, step: &Move) -> Result<(), String> {
+        match step.items.len() {
+            0 => return Err("The elevator cannot move empty".to_string()),
+            1 | 2 => {}
+            n => return Err(format!("The elevator can carry two items, not {}", n)),
+        }
+        if step.items.len() == 2 && step.items[0] == step.items[1] {
+            return Err(format!("The {} is carried twice", step.items[0]));
+        }
+
+        let from = self.elevator;
+        let to = match step.direction {
+            Direction::Up if from + 1 < FLOORS => from + 1,
+            Direction::Down if from > 0 => from - 1,
+            _ => {
+                return Err(format!(
+                    "The elevator cannot go {} from the {} floor",
+                    step.direction, FLOOR_NAMES[from]
+                ))
+            }
+        };
+
+        for item in &step.items {
+            let Some(idx) = self.floors[from].iter().position(|i| i == item) else {
+                return Err(format!(
+                    "The {} is not on the {} floor",
+                    item, FLOOR_NAMES[from]
+                ));
+            };
+            let item = self.floors[from].remove(idx);
+            self.floors[to].push(item);
+        }
+        self.elevator = to;
+
+        // Only the two floors the elevator was on can have changed
+        for floor in [from, to] {
+            let items = &self.floors[floor];
+            for item in items {
+                let Item::Microchip(element) = item else {
+                    continue;
+                };
+                if items.contains(&Item::Generator(element.clone())) {
+                    continue;
+                }
+                if let Some(generator) = items.iter().find(|i| matches!(i, Item::Generator(_))) {
+                    return Err(format!(
+                        "The {} is fried by the {} on the {} floor",
+                        item, generator, FLOOR_NAMES[floor]
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn state(&self) -> State {
+        State::new(self.elevator, pairs(&self.floors).unwrap())
+    }
+
+    fn is_done(&self) -> bool {
+        self.floors[..FLOORS - 1]
+            .iter()
+            .all(|items| items.is_empty())
+    }
+
+    // Every move the elevator could make from here, allowed or not
+    fn moves(&self) -> Vec<Move> {
+        let here = &self.floors[self.elevator];
+        let carried = here
+            .iter()
+            .map(|item| vec![item.clone()])
+            .chain(here.iter().cloned().combinations(2));
+
+        carried
+            .cartesian_product([Direction::Up, Direction::Down])
+            .map(|(items, direction)| Move { direction, items })
+            .collect()
+    }
+}
+
+// Checks a plan from the starting floors, reporting the first broken rule with its 1-based step
+fn validate(floors: &[Vec<Item>], plan: &[Move]) -> Result<(), String> {
+    // The same checks on the items as for the search
+    pairs(floors)?;
+
+    let mut building = Building::new(floors.to_vec());
+    for (idx, step) in plan.iter().enumerate() {
+        building
+            .apply(step)
+            .map_err(|e| format!("step {}: {}", idx + 1, e))?;
+    }
+
+    if !building.is_done() {
+        return Err(format!(
+            "Not everything is on the {} floor at the end",
+            FLOOR_NAMES[FLOORS - 1]
+        ));
+    }
+    Ok(())
+}
+
+// Checks a plan written as text against the puzzle input
+fn check_plan(input: &str, plan: &str) -> Result<(), String> {
+    validate(&parse(input)?, &parse_plan(plan)?)
+}
+
+// The fewest moves, with the actual items. The search only knows the states on the way, so each
+// move is whichever one leads from one state to the next.
+fn plan(floors: &[Vec<Item>]) -> Result<Vec<Move>, String> {
+    let path = shortest_path(pairs(floors)?)
+        .ok_or_else(|| "No way to bring everything to the top floor".to_string())?;
+
+    let mut building = Building::new(floors.to_vec());
+    let mut moves = Vec::new();
+    for next in &path[1..] {
+        let (step, after) = building
+            .moves()
+            .into_iter()
+            .find_map(|step| {
+                let mut after = building.clone();
+                (after.apply(&step).is_ok() && after.state() == *next).then_some((step, after))
+            })
+            .unwrap();
+
+        moves.push(step);
+        building = after;
+    }
+
+    Ok(moves)
+}
+
+fn search(pairs: Vec<Pair>) -> Result<u32, String> {
+    min_steps(pairs).ok_or_else(|| "No way to bring everything to the top floor".to_string())
 }
 
 #[aoc(day11, part1)]
-fn part1(input: &str) -> u32 {
-    let starting_counts = parse_starting_counts(input);
-    solve(starting_counts)
+fn part1(input: &str) -> Result<u32, String> {
+    search(pairs(&parse(input)?)?)
 }
 
 #[aoc(day11, part2)]
-fn part2(input: &str) -> u32 {
-    let starting_counts = parse_starting_counts(input);
+fn part2(input: &str) -> Result<u32, String> {
+    // The elerium and dilithium generators and microchips
+    let mut pairs = pairs(&parse(input)?)?;
+    pairs.extend([(0, 0), (0, 0)]);
+    search(pairs)
+}
+
+#[aoc(day11, part1, plan)]
+fn part1_plan(input: &str) -> Result<String, String> {
+    let moves = plan(&parse(input)?)?;
+    let listing = moves.iter().join("\n");
+    // Checked the same way as a plan written by hand
+    check_plan(input, &listing)?;
+
+    Ok(format!("{} moves\n{}", moves.len(), listing))
+}
+
+// Only counts the items per floor, so only right for inputs where no move has to wait for a
+// generator, like most puzzle inputs but not the sample
+#[aoc(day11, part1, formula)]
+fn part1_formula(input: &str) -> Result<u32, String> {
+    let starting_counts = starting_counts(&parse(input)?);
+    Ok(solve(starting_counts))
+}
+
+#[aoc(day11, part2, formula)]
+fn part2_formula(input: &str) -> Result<u32, String> {
+    let starting_counts = starting_counts(&parse(input)?);
     let with_extra_items = [
         starting_counts[0] + 4,
         starting_counts[1],
         starting_counts[2],
     ];
-    solve(with_extra_items)
+    Ok(solve(with_extra_items))
 }
 
 #[cfg(test)]
@@ -47,11 +510,185 @@ mod tests {
 
     #[test]
     fn test_solve_part1() {
-        assert_eq!(solve([2, 1, 1]), 9);
+        assert_eq!(part1(TEST_INPUT), Ok(11));
     }
 
     #[test]
-    fn test_solve_part2() {
+    fn test_formula() {
         assert_eq!(solve([6, 1, 1]), 33);
+
+        // The formula does not know the sample has to wait for its generators
+        assert_eq!(part1_formula(TEST_INPUT), Ok(9));
+        assert_eq!(part1_formula(FRIENDLY_INPUT), part1(FRIENDLY_INPUT));
+        assert_eq!(part2_formula(FRIENDLY_INPUT), part2(FRIENDLY_INPUT));
+    }
+
+    static TEST_INPUT: &str = "The first floor contains a hydrogen-compatible microchip and a lithium-compatible microchip.
+The second floor contains a hydrogen generator.
+The third floor contains a lithium generator.
+The fourth floor contains nothing relevant.";
+
+    // The shape most puzzle inputs have, where the formula holds
+    static FRIENDLY_INPUT: &str = "The first floor contains a thulium generator, a thulium-compatible microchip, a plutonium generator, and a strontium generator.
+The second floor contains a plutonium-compatible microchip and a strontium-compatible microchip.
+The third floor contains a promethium generator, a promethium-compatible microchip, a ruthenium generator, and a ruthenium-compatible microchip.
+The fourth floor contains nothing relevant.";
+
+    #[test]
+    fn test_parse() {
+        let hydrogen = || Element("hydrogen".to_string());
+        let lithium = || Element("lithium".to_string());
+        assert_eq!(
+            parse(TEST_INPUT),
+            Ok(vec![
+                vec![Item::Microchip(hydrogen()), Item::Microchip(lithium())],
+                vec![Item::Generator(hydrogen())],
+                vec![Item::Generator(lithium())],
+                vec![],
+            ])
+        );
+
+        // With and without the Oxford comma
+        let floors = parse(FRIENDLY_INPUT).unwrap();
+        assert_eq!(starting_counts(&floors), [4, 2, 4]);
+        let floor = "The first floor contains a thulium generator, an elerium generator and a \
+                     plutonium-compatible microchip.";
+        assert_eq!(parse_floor(floor, "first").unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |first_floor: &str| {
+            let input = TEST_INPUT.replacen(TEST_INPUT.lines().next().unwrap(), first_floor, 1);
+            parse(&input).unwrap_err()
+        };
+
+        assert_eq!(
+            error("The first floor contains a lithium reactor."),
+            "line 1: Unknown item: a lithium reactor"
+        );
+        assert_eq!(
+            error("The first floor contains a lithium microchip."),
+            "line 1: Unknown microchip: a lithium microchip"
+        );
+        assert_eq!(
+            error("The ground floor contains nothing relevant."),
+            "line 1: Expected the first floor: The ground floor contains nothing relevant."
+        );
+        assert_eq!(
+            error("The first floor contains nothing relevant"),
+            "line 1: Expected a full stop: The first floor contains nothing relevant"
+        );
+        assert_eq!(
+            parse("The first floor contains nothing relevant."),
+            Err("Expected 4 floors, got 1".to_string())
+        );
+        assert_eq!(
+            pairs(&parse(&TEST_INPUT.replace("a lithium generator", "nothing relevant")).unwrap()),
+            Err("No generator for the lithium microchip".to_string())
+        );
+        assert_eq!(
+            pairs(
+                &parse(&TEST_INPUT.replace("a hydrogen generator", "a lithium generator")).unwrap()
+            ),
+            Err("More than one lithium generator".to_string())
+        );
+        assert_eq!(
+            part1(&TEST_INPUT.replace("hydrogen-compatible", "lithium-compatible")),
+            Err("More than one lithium microchip".to_string())
+        );
+    }
+
+    #[test]
+    fn test_search() {
+        assert_eq!(part1(FRIENDLY_INPUT), Ok(solve([4, 2, 4])));
+        assert_eq!(part2(FRIENDLY_INPUT), Ok(solve([8, 2, 4])));
+    }
+
+    // The moves from the puzzle description
+    static TEST_PLAN: &str = "up: hydrogen microchip
+up: hydrogen generator, hydrogen microchip
+down: hydrogen microchip
+down: hydrogen microchip
+up: hydrogen microchip, lithium microchip
+up: hydrogen microchip, lithium microchip
+up: hydrogen microchip, lithium microchip
+down: hydrogen microchip
+up: hydrogen generator, lithium generator
+down: lithium microchip
+up: hydrogen microchip, lithium microchip";
+
+    #[test]
+    fn test_plan() {
+        assert_eq!(check_plan(TEST_INPUT, TEST_PLAN), Ok(()));
+
+        let moves = plan(&parse(TEST_INPUT).unwrap()).unwrap();
+        assert_eq!(moves.len(), 11);
+        assert_eq!(moves[0].to_string(), "up: hydrogen microchip");
+        assert!(part1_plan(TEST_INPUT).unwrap().starts_with("11 moves\n"));
+
+        let floors = parse(FRIENDLY_INPUT).unwrap();
+        assert_eq!(validate(&floors, &plan(&floors).unwrap()), Ok(()));
+    }
+
+    #[test]
+    fn test_plan_violations() {
+        let check = |plan: &str| check_plan(TEST_INPUT, plan).unwrap_err();
+
+        assert_eq!(
+            check("up: hydrogen microchip, lithium microchip, hydrogen generator"),
+            "step 1: The elevator can carry two items, not 3"
+        );
+        assert_eq!(
+            check("up: hydrogen microchip\nup:"),
+            "step 2: The elevator cannot move empty"
+        );
+        assert_eq!(
+            check("up: hydrogen microchip, hydrogen microchip"),
+            "step 1: The hydrogen microchip is carried twice"
+        );
+        assert_eq!(
+            check("down: hydrogen microchip"),
+            "step 1: The elevator cannot go down from the first floor"
+        );
+        assert_eq!(
+            check("up: lithium generator"),
+            "step 1: The lithium generator is not on the first floor"
+        );
+        assert_eq!(
+            check("up: hydrogen microchip\nup: hydrogen microchip"),
+            "step 2: The hydrogen microchip is fried by the lithium generator on the third floor"
+        );
+        assert_eq!(
+            check(&TEST_PLAN.lines().take(3).join("\n")),
+            "Not everything is on the fourth floor at the end"
+        );
+        assert_eq!(
+            check("sideways: lithium microchip"),
+            "step 1: Unknown direction: sideways"
+        );
+        assert_eq!(
+            check("up: hydrogen reactor"),
+            "step 1: Unknown item: hydrogen reactor"
+        );
+    }
+
+    #[test]
+    fn test_chip_fry_rule() {
+        assert!(State::new(0, vec![(0, 0), (0, 0)]).is_safe());
+        assert!(State::new(0, vec![(1, 0), (1, 1)]).is_safe());
+        assert!(!State::new(0, vec![(1, 0), (0, 0)]).is_safe());
+
+        assert_eq!(min_steps(vec![(0, 0)]), Some(3));
+        // The lone microchip can never get past the other generator
+        assert_eq!(min_steps(vec![(3, 0), (2, 2)]), None);
+    }
+
+    #[test]
+    fn test_canonical_states() {
+        assert_eq!(
+            State::new(1, vec![(2, 1), (0, 0)]),
+            State::new(1, vec![(0, 0), (2, 1)])
+        );
     }
 }
@@ -1,72 +1,42 @@
 use aoc_runner_derive::aoc;
-use std::collections::HashMap;
 
-fn get_value(s: &str, registers: &HashMap<&str, i32>) -> i32 {
-    if let Some(value) = registers.get(s) {
-        return *value;
-    } else if let Ok(value) = s.parse::<i32>() {
-        return value;
-    } else {
-        panic!("Unknown value: {}", s);
-    }
-}
+use aoc2016::assembunny::opcodes::InstructionSet;
+use aoc2016::assembunny::threaded::Threaded;
+use aoc2016::assembunny::Machine;
 
-fn run<'a>(ops: Vec<&'a str>, registers: &mut HashMap<&'a str, i32>) {
-    let mut pc = 0_i32;
-
-    while let Some(cmd) = ops.get(pc as usize) {
-        let (op, rest) = cmd.split_once(' ').unwrap();
+#[aoc(day12, part1)]
+fn part1(input: &str) -> Result<i32, String> {
+    let mut machine = Machine::new(InstructionSet::day12().parse(input)?, [0, 0, 0, 0]);
 
-        match op {
-            "cpy" => {
-                let (x, y) = rest.split_once(' ').unwrap();
-                let value = get_value(x, &registers);
-                registers.insert(y, value);
-                pc += 1;
-            }
-            "inc" => {
-                let value = get_value(rest, &registers);
-                registers.insert(rest, value + 1);
-                pc += 1;
-            }
-            "dec" => {
-                let value = get_value(rest, &registers);
-                registers.insert(rest, value - 1);
-                pc += 1;
-            }
-            "jnz" => {
-                let (x, y) = rest.split_once(' ').unwrap();
-                let value = get_value(x, &registers);
+    machine.optimize();
+    machine.run_checked()?;
 
-                if value != 0 {
-                    pc += y.parse::<i32>().unwrap();
-                } else {
-                    pc += 1;
-                }
-            }
-            _ => panic!("Unknown op code: {}", op),
-        }
-    }
+    Ok(machine.registers[0])
 }
 
-#[aoc(day12, part1)]
-fn part1(input: &str) -> i32 {
-    let ops: Vec<&str> = input.lines().collect();
-    let mut registers = HashMap::from([("a", 0), ("b", 0), ("c", 0), ("d", 0)]);
+#[aoc(day12, part2)]
+fn part2(input: &str) -> Result<i32, String> {
+    let mut machine = Machine::new(InstructionSet::day12().parse(input)?, [0, 0, 1, 0]);
 
-    run(ops, &mut registers);
+    machine.optimize();
+    machine.run_checked()?;
 
-    *registers.get("a").unwrap()
+    Ok(machine.registers[0])
 }
 
-#[aoc(day12, part2)]
-fn part2(input: &str) -> i32 {
-    let ops: Vec<&str> = input.lines().collect();
-    let mut registers = HashMap::from([("a", 0), ("b", 0), ("c", 1), ("d", 0)]);
-
-    run(ops, &mut registers);
+// Without the fused loops, to compare the backends with 'cargo aoc bench'
+#[aoc(day12, part1, threaded)]
+fn part1_threaded(input: &str) -> Result<i32, String> {
+    let mut threaded = Threaded::new(InstructionSet::day12().parse(input)?, [0, 0, 0, 0]);
+    threaded.run();
+    Ok(threaded.state.registers[0])
+}
 
-    *registers.get("a").unwrap()
+#[aoc(day12, part2, threaded)]
+fn part2_threaded(input: &str) -> Result<i32, String> {
+    let mut threaded = Threaded::new(InstructionSet::day12().parse(input)?, [0, 0, 1, 0]);
+    threaded.run();
+    Ok(threaded.state.registers[0])
 }
 
 #[cfg(test)]
@@ -83,6 +53,27 @@ dec a
 
     #[test]
     fn test_part1() {
-        assert_eq!(part1(TEST_INPUT), 42);
+        assert_eq!(part1(TEST_INPUT), Ok(42));
+        assert_eq!(part1_threaded(TEST_INPUT), Ok(42));
+    }
+
+    #[test]
+    fn test_overflow() {
+        assert_eq!(
+            part2("cpy 2147483647 a\ninc a"),
+            Err("Overflow at 1: inc a".to_string())
+        );
+    }
+
+    #[test]
+    fn test_day_23_opcodes_rejected() {
+        assert_eq!(part1("tgl a"), Err("Unknown op code: tgl a".to_string()));
+        assert_eq!(
+            part2_threaded(
+                "cpy 1 a
+out a"
+            ),
+            Err("Unknown op code: out a".to_string())
+        );
     }
 }
@@ -1,119 +1,106 @@
-use std::collections::HashMap;
+use std::fmt;
 
 use aoc_runner_derive::aoc;
 
-fn get_value(s: &str, registers: &HashMap<String, i32>) -> i32 {
-    if let Some(value) = registers.get(s) {
-        return *value;
-    } else if let Ok(value) = s.parse::<i32>() {
-        return value;
-    } else {
-        panic!("Unknown value: {}", s);
-    }
+use aoc2016::assembunny::opcodes::InstructionSet;
+use aoc2016::assembunny::symbolic::{self, Expr};
+use aoc2016::assembunny::{Instruction, Machine, Toggle};
+
+// What a run leaves behind: the registers, the program as 'tgl' left it and every toggle on the
+// way
+struct Run {
+    registers: [i64; 4],
+    program: Vec<Instruction>,
+    toggles: Vec<Toggle>,
 }
 
-fn run(ops: &mut Vec<String>, registers: &mut HashMap<String, i32>) {
-    let mut pc = 0_i32;
-
-    while pc >= 0 && pc < ops.len() as i32 {
-        let cmd = ops.get(pc as usize).unwrap().clone();
-
-        let (op, rest) = cmd.split_once(' ').unwrap();
-
-        match op {
-            "cpy" => {
-                let (x, y) = rest.split_once(' ').unwrap();
-                let value = get_value(x, &registers);
-                registers.insert(y.to_owned(), value);
-                pc += 1;
-            }
-            "inc" => {
-                let value = get_value(rest, &registers);
-                registers.insert(rest.to_owned(), value + 1);
-                pc += 1;
-            }
-            "dec" => {
-                let value = get_value(rest, &registers);
-                registers.insert(rest.to_owned(), value - 1);
-                pc += 1;
-            }
-            "jnz" => {
-                let (x, y) = rest.split_once(' ').unwrap();
-                let value = get_value(x, &registers);
-
-                if value != 0 {
-                    pc += get_value(y, &registers);
-                } else {
-                    pc += 1;
-                }
-            }
-            "tgl" => {
-                let value = get_value(rest, &registers);
-                let target_idx = (pc + value) as usize;
-                if target_idx < ops.len() {
-                    let target = &ops[target_idx];
-                    let parts: Vec<_> = target.split(' ').collect();
-                    // println!("toggling target: {}, {:?}", target, parts);
-
-                    ops[target_idx] = match (parts.len(), parts[0]) {
-                        (2, "inc") => {
-                            format!("dec {}", parts[1])
-                        }
-                        (2, _) => {
-                            format!("inc {}", parts[1])
-                        }
-                        (3, "jnz") => {
-                            format!("cpy {} {}", parts[1], parts[2])
-                        }
-                        (3, _) => {
-                            format!("jnz {} {}", parts[1], parts[2])
-                        }
-                        _ => panic!("Unknown target cmd to toggle: {}", target),
-                    };
-
-                    // println!("new cmd: {}", ops[target_idx]);
-                }
-
-                pc += 1;
-            }
-            _ => panic!("Unknown op code: {}", op),
+impl fmt::Display for Run {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (pc, instruction) in self.program.iter().enumerate() {
+            writeln!(f, "{:3}  {}", pc, instruction)?;
         }
+        for toggle in &self.toggles {
+            writeln!(f, "{}", toggle)?;
+        }
+        Ok(())
     }
 }
 
+fn run(program: Vec<Instruction>, a: i64) -> Result<Run, String> {
+    let mut machine = Machine::new(program, [a, 0, 0, 0]);
+
+    machine.optimize();
+    machine.run_checked()?;
+
+    Ok(Run {
+        registers: machine.registers,
+        program: machine.program,
+        toggles: machine.toggles,
+    })
+}
+
 #[aoc(day23, part1)]
-fn part1(input: &str) -> i32 {
-    let mut ops: Vec<String> = input.lines().map(|s| s.to_owned()).collect();
-    let mut registers = HashMap::from([
-        ("a".to_owned(), 7),
-        ("b".to_owned(), 0),
-        ("c".to_owned(), 0),
-        ("d".to_owned(), 0),
-    ]);
-
-    run(&mut ops, &mut registers);
-
-    *registers.get("a").unwrap()
+fn part1(input: &str) -> Result<i64, String> {
+    Ok(run(InstructionSet::day23().parse(input)?, 7)?.registers[0])
 }
 
 #[aoc(day23, part2)]
-fn part2(input: &str) -> i32 {
-    let mut ops: Vec<String> = input.lines().map(|s| s.to_owned()).collect();
-    let mut registers = HashMap::from([
-        ("a".to_owned(), 12),
-        ("b".to_owned(), 0),
-        ("c".to_owned(), 0),
-        ("d".to_owned(), 0),
-    ]);
-
-    run(&mut ops, &mut registers);
-
-    *registers.get("a").unwrap()
+fn part2(input: &str) -> Result<i64, String> {
+    // 12! is already close to the limit of i32
+    Ok(run(InstructionSet::day23().parse(input)?, 12)?.registers[0])
+}
+
+// What part 1 turned the program into, and how
+#[aoc(day23, part1, log)]
+fn part1_log(input: &str) -> Result<String, String> {
+    Ok(run(InstructionSet::day23().parse(input)?, 7)?.to_string())
+}
+
+// The program computes a! and meanwhile toggles its own tail into adding a product of two
+// constants to it. The toggled tail is the same for any a large enough to reach it, so it is
+// taken from a run with a = 7 and then executed symbolically from just past the 'tgl'.
+fn solve_symbolically(program: &[Instruction], a: i64) -> Result<i64, String> {
+    let mut machine = Machine::new(program.to_vec(), [7, 0, 0, 0]);
+    machine.optimize();
+    machine.run_checked()?;
+
+    let start = machine
+        .program
+        .iter()
+        .rposition(|i| matches!(i, Instruction::Tgl(_)))
+        .ok_or("No tgl in the program")?
+        + 1;
+    let state = symbolic::execute(
+        &machine.program,
+        start,
+        symbolic::symbols(),
+        machine.program.len(),
+    )?;
+
+    let added = (state.registers[0].clone() - Expr::register(0))
+        .as_constant()
+        .ok_or_else(|| format!("Expected a + k at the end, got {}", state.registers[0]))?;
+
+    (1..=a)
+        .try_fold(1i64, |product, n| product.checked_mul(n))
+        .and_then(|product| product.checked_add(added))
+        .ok_or_else(|| format!("{}! + {} does not fit an i64", a, added))
+}
+
+#[aoc(day23, part1, symbolic)]
+fn part1_symbolic(input: &str) -> Result<i64, String> {
+    solve_symbolically(&InstructionSet::day23().parse(input)?, 7)
+}
+
+#[aoc(day23, part2, symbolic)]
+fn part2_symbolic(input: &str) -> Result<i64, String> {
+    solve_symbolically(&InstructionSet::day23().parse(input)?, 12)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use aoc2016::assembunny::parse;
 
     static TEST_INPUT: &str = "cpy 2 a
 tgl a
@@ -126,6 +113,83 @@ dec a
 
     #[test]
     fn test_part1() {
-        assert_eq!(part1(TEST_INPUT), 3);
+        assert_eq!(part1(TEST_INPUT), Ok(3));
+        assert_eq!(
+            run(parse("inc a\ntgl 0"), i64::MAX).err(),
+            Some("Overflow at 0: inc a".to_string())
+        );
+    }
+
+    #[test]
+    fn test_run() {
+        let result = run(parse(TEST_INPUT), 7).unwrap();
+        assert_eq!(
+            result.program,
+            parse("cpy 2 a\ntgl a\ntgl a\ninc a\njnz 1 a\ndec a\ndec a")
+        );
+
+        let log: Vec<String> = result.toggles.iter().map(|t| t.to_string()).collect();
+        assert_eq!(
+            log,
+            [
+                "cycle 1: tgl at 1 turned 3 from 'tgl a' into 'inc a'",
+                "cycle 2: tgl at 2 turned 4 from 'cpy 1 a' into 'jnz 1 a'",
+            ]
+        );
+
+        let result = run(parse("inc a\ninc a\ntgl a\ntgl -3"), 0).unwrap();
+        assert_eq!(result.program, parse("dec a\ninc a\ntgl a\ntgl -3"));
+        let log: Vec<String> = result.toggles.iter().map(|t| t.to_string()).collect();
+        assert_eq!(
+            log,
+            [
+                "cycle 2: tgl at 2 skipped 4, outside the program",
+                "cycle 3: tgl at 3 turned 0 from 'inc a' into 'dec a'",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_log() {
+        assert_eq!(
+            part1_log(TEST_INPUT),
+            Ok("  0  cpy 2 a
+  1  tgl a
+  2  tgl a
+  3  inc a
+  4  jnz 1 a
+  5  dec a
+  6  dec a
+cycle 1: tgl at 1 turned 3 from 'tgl a' into 'inc a'
+cycle 2: tgl at 2 turned 4 from 'cpy 1 a' into 'jnz 1 a'
+"
+            .to_string())
+        );
+    }
+
+    #[test]
+    fn test_toggle_cycles_count_fused_loops() {
+        let program = parse(include_str!("assembunny/fixtures/factorial.txt"));
+        let optimized = run(program.clone(), 7).unwrap();
+
+        let mut machine = Machine::new(program, [7, 0, 0, 0]);
+        machine.run();
+        assert_eq!(optimized.toggles, machine.toggles);
+        assert!(optimized.toggles.iter().all(|t| t.cycle > 100));
+    }
+
+    #[test]
+    fn test_solve_symbolically() {
+        // Same shape as the puzzle input, computing a! + 3 * 4
+        let program = parse(include_str!("assembunny/fixtures/factorial.txt"));
+
+        assert_eq!(solve_symbolically(&program, 7), Ok(5052));
+        assert_eq!(solve_symbolically(&program, 12), Ok(479001612));
+        assert_eq!(solve_symbolically(&program, 20), Ok(2432902008176640012));
+        assert_eq!(
+            solve_symbolically(&program, 21),
+            Err("21! + 12 does not fit an i64".to_string())
+        );
+        assert_eq!(solve_symbolically(&parse(TEST_INPUT), 7).ok(), None);
     }
 }
@@ -1,93 +1,115 @@
-use std::collections::HashMap;
-use std::iter;
+use std::collections::HashSet;
+use std::ops::{Bound, RangeBounds};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::thread;
 
 use aoc_runner_derive::aoc;
 
-fn get_value(s: &str, registers: &HashMap<&str, i32>) -> i32 {
-    if let Some(value) = registers.get(s) {
-        return *value;
-    } else if let Ok(value) = s.parse::<i32>() {
-        return value;
-    } else {
-        panic!("Unknown value: {}", s);
-    }
-}
+use aoc2016::assembunny::opcodes::InstructionSet;
+use aoc2016::assembunny::output::{Alternating, Sink, Verdict};
+use aoc2016::assembunny::symbolic::{self, Expr};
+use aoc2016::assembunny::{Instruction, Machine, Operand};
 
-fn run<'a>(
-    ops: &Vec<&'a str>,
-    registers: &mut HashMap<&'a str, i32>,
-    signal_length: usize,
-) -> Vec<i32> {
-    let mut pc = 0_i32;
+// Signals that alternate this long without the machine ever repeating a state are given up on,
+// as they are not proven to go on forever.
+const MAX_SIGNAL_LENGTH: usize = 100_000;
 
-    let mut signal = Vec::new();
+// A program that runs this long without an 'out' is taken to be stuck rather than slow
+const MAX_STEPS_PER_OUTPUT: usize = 1_000_000;
 
-    while let Some(cmd) = ops.get(pc as usize) {
-        let (op, rest) = cmd.split_once(' ').unwrap();
+// The values of 'a' tried by 'part1', as far as the original brute force went
+const MAX_A: i32 = 1_000;
 
-        match op {
-            "cpy" => {
-                let (x, y) = rest.split_once(' ').unwrap();
-                let value = get_value(x, &registers);
-                registers.insert(y, value);
-                pc += 1;
-            }
-            "inc" => {
-                let value = get_value(rest, &registers);
-                registers.insert(rest, value + 1);
-                pc += 1;
-            }
-            "dec" => {
-                let value = get_value(rest, &registers);
-                registers.insert(rest, value - 1);
-                pc += 1;
-            }
-            "jnz" => {
-                let (x, y) = rest.split_once(' ').unwrap();
-                let value = get_value(x, &registers);
-
-                if value != 0 {
-                    pc += y.parse::<i32>().unwrap();
-                } else {
-                    pc += 1;
-                }
-            }
-            "out" => {
-                let value = get_value(rest, &registers);
-                signal.push(value);
-                pc += 1;
+// Runs the program until the signal either breaks the 0, 1, 0, 1... pattern or the machine is
+// back in a state it already was in at an earlier 'out'. From a repeated state the same outputs
+// follow again, so the pattern is then proven to go on forever.
+fn produces_clock_signal(program: &[Instruction], a: i32) -> bool {
+    let mut machine = Machine::new(program.to_vec(), [a, 0, 0, 0]);
+    machine.optimize();
+
+    let mut alternating = Alternating::new(MAX_SIGNAL_LENGTH);
+    let mut seen = HashSet::new();
+    let mut signal_length = 0;
+    let mut steps = 0;
+
+    while machine.step() {
+        let Some(value) = machine.output.pop() else {
+            steps += 1;
+            if steps > MAX_STEPS_PER_OUTPUT {
+                return false;
             }
-            _ => panic!("Unknown op code: {}", op),
+            continue;
+        };
+        steps = 0;
+        signal_length += 1;
+
+        if alternating.write(value) != Verdict::Undecided {
+            return false;
         }
 
-        if signal.len() == signal_length {
-            return signal;
+        // Which bit comes next is part of the state too
+        if !seen.insert((machine.state(), signal_length % 2)) {
+            return true;
         }
     }
 
-    panic!("Failed to create full signal")
+    false
 }
 
-#[aoc(day25, part1)]
-fn part1(input: &str) -> i32 {
-    let ops: Vec<&str> = input.lines().collect();
+// Candidates are handed out to the workers this many at a time, in increasing order
+const CHUNK_SIZE: i64 = 16;
 
-    let signal_length = 20_usize;
+// Searches 'range' for the lowest 'a' that produces a clock signal, with the chunks spread over
+// all cores. A worker gives up on its chunk as soon as a lower value has been found elsewhere, and
+// stops taking new chunks once they start above it. Every chunk below the result has then been
+// checked in full, so the result is the lowest valid value.
+fn find_lowest(program: &[Instruction], range: impl RangeBounds<i32>) -> Result<i32, String> {
+    let start = match range.start_bound() {
+        Bound::Included(&start) => start as i64,
+        Bound::Excluded(&start) => start as i64 + 1,
+        // Negative values only when asked for
+        Bound::Unbounded => 0,
+    };
+    let end = match range.end_bound() {
+        Bound::Included(&end) => end as i64 + 1,
+        Bound::Excluded(&end) => end as i64,
+        Bound::Unbounded => i32::MAX as i64 + 1,
+    };
 
-    for i in 0..1_000 {
-        let mut registers = HashMap::from([("a", i), ("b", 0), ("c", 0), ("d", 0)]);
+    let next_chunk = AtomicI64::new(start);
+    let lowest = AtomicI64::new(i64::MAX);
+    let workers = thread::available_parallelism().map_or(1, |n| n.get());
 
-        let signal = run(&ops, &mut registers, signal_length);
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let chunk = next_chunk.fetch_add(CHUNK_SIZE, Ordering::SeqCst);
+                if chunk >= end || chunk >= lowest.load(Ordering::SeqCst) {
+                    return;
+                }
 
-        // Lazy brute force
-        let expected: Vec<i32> = iter::repeat(vec![0, 1])
-            .flatten()
-            .take(signal_length)
-            .collect();
-        if signal == expected {
-            return i;
+                for a in chunk..(chunk + CHUNK_SIZE).min(end) {
+                    if a >= lowest.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    if produces_clock_signal(program, a as i32) {
+                        lowest.fetch_min(a, Ordering::SeqCst);
+                        break;
+                    }
+                }
+            });
         }
+    });
+
+    match lowest.into_inner() {
+        i64::MAX => Err(format!("No clock signal for a in {}..{}", start, end)),
+        a => Ok(a as i32),
     }
+}
+
+#[aoc(day25, part1)]
+fn part1(input: &str) -> Result<i32, String> {
+    find_lowest(&InstructionSet::day25().parse(input)?, 0..MAX_A)
 
     /*
     Proper solution:
@@ -106,13 +128,47 @@ fn part1(input: &str) -> i32 {
 
     The value needed for register 'a': 2730 - (a * b)
     */
+}
+
+// The proper solution from above, for any constants. The program ends in an endless loop that
+// prints 'd' in binary, lowest bit first. Running the setup before it symbolically has to leave
+// 'd = a + k', so 'a' has to make up the difference to the next number with bits 0b...1010.
+fn solve_symbolically(program: &[Instruction]) -> Result<i32, String> {
+    let head = match program.last() {
+        Some(&Instruction::Jnz(Operand::Value(x), Operand::Value(offset))) if x != 0 => {
+            program.len() as i32 - 1 + offset
+        }
+        _ => -1,
+    };
+    if head < 0 {
+        return Err("Expected the program to end in an endless loop".to_string());
+    }
+
+    let state = symbolic::execute(program, 0, symbolic::symbols(), head as usize)?;
+    let d = &state.registers[3];
+    let k = (d.clone() - Expr::register(0))
+        .as_constant()
+        .ok_or_else(|| format!("Expected d = a + k after the setup, got d = {}", d))?;
+
+    let mut signal = 0b10;
+    while signal < k {
+        signal = (signal << 2) | 0b10;
+    }
+
+    Ok((signal - k) as i32)
+}
 
-    panic!("No result found under 1_000")
+#[aoc(day25, part1, symbolic)]
+fn part1_symbolic(input: &str) -> Result<i32, String> {
+    solve_symbolically(&InstructionSet::day25().parse(input)?)
 }
 
 #[cfg(test)]
 mod tests {
+    use std::iter;
+
     use super::*;
+    use aoc2016::assembunny::parse;
 
     // Printing part of the ops
     static TEST_INPUT: &str = "cpy d a
@@ -139,10 +195,15 @@ jnz a -19
 jnz 1 -21";
 
     fn gen_signal(input: &str, d: i32, signal_length: usize) -> Vec<i32> {
-        let ops: Vec<&str> = input.lines().collect();
-        let mut registers = HashMap::from([("a", 0), ("b", 0), ("c", 0), ("d", d)]);
+        let mut machine = Machine::new(parse(input), [0, 0, 0, d]);
+
+        while machine.output.len() < signal_length {
+            if !machine.step() {
+                panic!("Failed to create full signal")
+            }
+        }
 
-        run(&ops, &mut registers, signal_length)
+        machine.output
     }
 
     #[test]
@@ -153,4 +214,60 @@ jnz 1 -21";
         let expected: Vec<i32> = iter::repeat(vec![0, 1]).flatten().take(n).collect();
         assert_eq!(gen_signal(TEST_INPUT, 2730, n), expected);
     }
+
+    #[test]
+    fn test_produces_clock_signal() {
+        // Same as the printing part, but 'd' comes from 'a'
+        let program = parse(&format!("cpy a d\n{}", TEST_INPUT));
+
+        assert!(produces_clock_signal(&program, 2730));
+        assert!(produces_clock_signal(&program, 10));
+        assert!(!produces_clock_signal(&program, 2731));
+        assert!(!produces_clock_signal(&program, 5));
+        // 0, 1, 0, 1, 0, 1, 1
+        assert!(!produces_clock_signal(&program, 0b1101010));
+
+        // Never prints anything, nor repeats a state
+        assert!(!produces_clock_signal(&parse("inc b\njnz 1 -1"), 0));
+    }
+
+    #[test]
+    fn test_find_lowest() {
+        let program = parse(&format!("cpy a d\n{}", TEST_INPUT));
+
+        assert_eq!(find_lowest(&program, ..), Ok(2));
+        assert_eq!(find_lowest(&program, 3..), Ok(10));
+        assert_eq!(find_lowest(&program, 11..=42), Ok(42));
+        assert_eq!(find_lowest(&program, 43..=1_000), Ok(170));
+        assert_eq!(
+            find_lowest(&program, 11..42),
+            Err("No clock signal for a in 11..42".to_string())
+        );
+    }
+
+    #[test]
+    fn test_solve_symbolically() {
+        // Setup with the same shape as the puzzle input, d = a + 4 * 643
+        let program = parse(&format!(
+            "cpy a d
+cpy 4 c
+cpy 643 b
+inc d
+dec b
+jnz b -2
+dec c
+jnz c -5
+{}",
+            TEST_INPUT
+        ));
+
+        assert_eq!(solve_symbolically(&program), Ok(158));
+        assert!(produces_clock_signal(&program, 158));
+        assert!(!produces_clock_signal(&program, 157));
+
+        assert_eq!(
+            solve_symbolically(&parse("cpy a d\ninc d\njnz b -1")),
+            Err("Expected the program to end in an endless loop".to_string())
+        );
+    }
 }
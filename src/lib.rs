@@ -0,0 +1,2 @@
+// Shared by the puzzle solutions in main.rs and the tools in src/bin
+pub mod assembunny;
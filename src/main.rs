@@ -12,7 +12,7 @@ mod day_4;
 // mod day_5; // slow
 mod day_10;
 mod day_11;
-// mod day_12; // slow
+mod day_12;
 mod day_13;
 // mod day_14; // slow
 mod day_15;
@@ -23,7 +23,7 @@ mod day_19;
 mod day_20;
 mod day_21;
 mod day_22;
-// mod day_23; // slow
+mod day_23;
 mod day_24;
 mod day_25;
 mod day_6;